@@ -0,0 +1,168 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use std::env;
+
+use crate::error::{MessageError, Result};
+use crate::messages::now_millis;
+use crate::store::MessageStore;
+
+// Secret used to sign login tokens, at least MIN_TOKEN_KEY_LENGTH bytes. Every
+// instance, and anything else checking the tokens, needs the same one.
+pub const TOKEN_KEY_VAR: &str = "MESSAGE_TOKEN_KEY";
+
+const MIN_TOKEN_KEY_LENGTH: usize = 32;
+const TOKEN_LIFETIME_SECONDS: u64 = 24 * 60 * 60;
+const MAX_USERNAME_LENGTH: usize = 32;
+const MIN_PASSWORD_LENGTH: usize = 8;
+// Argon2 hashes whatever it's given, so long inputs cost real CPU time
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+// Issues and checks login tokens. A token is `<user_id>.<expires_at>.<signature>`
+// where expires_at is in seconds since the Unix epoch and the signature is the
+// hex HMAC-SHA256 of `<user_id>.<expires_at>` under the token key.
+pub struct Tokens {
+    key: Vec<u8>,
+}
+
+impl Tokens {
+    pub fn from_env() -> std::result::Result<Tokens, String> {
+        let key = env::var(TOKEN_KEY_VAR).map_err(|_| format!("{} is not set", TOKEN_KEY_VAR))?;
+        Tokens::new(key.into_bytes())
+    }
+
+    pub fn new(key: Vec<u8>) -> std::result::Result<Tokens, String> {
+        if key.len() < MIN_TOKEN_KEY_LENGTH {
+            return Err(format!("{} should be at least {} bytes", TOKEN_KEY_VAR, MIN_TOKEN_KEY_LENGTH));
+        }
+        Ok(Tokens { key })
+    }
+
+    // The token and when it expires
+    pub fn issue(&self, user_id: &str) -> (String, u64) {
+        let expires_at = now_millis() / 1000 + TOKEN_LIFETIME_SECONDS;
+        let claims = format!("{}.{}", user_id, expires_at);
+        let signature = hex(&self.mac(&claims).finalize().into_bytes());
+        (format!("{}.{}", claims, signature), expires_at)
+    }
+
+    // The user the token was issued to
+    pub fn verify(&self, token: &str) -> Result<String> {
+        let invalid = || MessageError::Unauthorized("the token is invalid or has expired".to_string());
+        let (claims, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let signature = unhex(signature).ok_or_else(invalid)?;
+        // verify_slice compares in constant time
+        self.mac(claims).verify_slice(&signature).map_err(|_| invalid())?;
+        let (user_id, expires_at) = claims.rsplit_once('.').ok_or_else(invalid)?;
+        let expires_at: u64 = expires_at.parse().map_err(|_| invalid())?;
+        if expires_at <= now_millis() / 1000 {
+            return Err(invalid());
+        }
+        Ok(user_id.to_string())
+    }
+
+    fn mac(&self, claims: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(claims.as_bytes());
+        mac
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn check_username(user_name: &str) -> Result<()> {
+    let length = user_name.chars().count();
+    if length == 0 || length > MAX_USERNAME_LENGTH {
+        return Err(MessageError::BadRequest(format!(
+            "username should be between 1 and {} characters",
+            MAX_USERNAME_LENGTH
+        )));
+    }
+    if user_name.trim() != user_name || user_name.chars().any(char::is_control) {
+        return Err(MessageError::BadRequest(
+            "username can't start or end with spaces or contain control characters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn check_password(password: &str) -> Result<()> {
+    let length = password.chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        return Err(MessageError::BadRequest(format!(
+            "password should be between {} and {} characters",
+            MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+// Hashing is slow on purpose, so it runs off the async workers
+async fn hash_password(password: &str) -> Result<String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(MessageError::backend)
+    })
+    .await
+    .map_err(MessageError::backend)?
+}
+
+async fn password_matches(password_hash: &str, password: &str) -> Result<bool> {
+    let (password_hash, password) = (password_hash.to_string(), password.to_string());
+    tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&password_hash).map_err(MessageError::backend)?;
+        Ok(Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+    })
+    .await
+    .map_err(MessageError::backend)?
+}
+
+fn session(tokens: &Tokens, user_id: &str) -> String {
+    let (token, expires_at) = tokens.issue(user_id);
+    json!({"user_id": user_id, "token": token, "expires_at": expires_at}).to_string()
+}
+
+// Creates the account and logs straight in, returning the same JSON as login
+pub async fn create_account(
+    store: &dyn MessageStore,
+    tokens: &Tokens,
+    user_name: &str,
+    password: &str,
+) -> Result<String> {
+    check_username(user_name)?;
+    check_password(password)?;
+    let password_hash = hash_password(password).await?;
+    let user = store.create_user(user_name, &password_hash).await?;
+    Ok(session(tokens, &user.user_id))
+}
+
+// `{"user_id", "token", "expires_at"}` for the right password. Unknown users
+// and wrong passwords get the same error.
+pub async fn login(store: &dyn MessageStore, tokens: &Tokens, user_name: &str, password: &str) -> Result<String> {
+    let rejected = || MessageError::Unauthorized("wrong username or password".to_string());
+    let user = store.find_user(user_name).await?.ok_or_else(rejected)?;
+    let password_hash = user.password_hash.as_deref().ok_or_else(rejected)?;
+    if !password_matches(password_hash, password).await? {
+        return Err(rejected());
+    }
+    Ok(session(tokens, &user.user_id))
+}
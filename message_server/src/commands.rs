@@ -0,0 +1,450 @@
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+
+use crate::auth::{self, Tokens};
+use crate::error::{MessageError, Result};
+use crate::messages;
+use crate::moderation::{self, Moderation};
+use crate::store::MessageStore;
+
+#[derive(Deserialize, Default)]
+struct WriteMessageContent {
+    token: String,
+    message: String,
+    recipient_id: Option<String>,
+    reply_to: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct MarkReadContent {
+    token: String,
+    message_id: String,
+}
+
+#[derive(Deserialize, Default)]
+struct GetConversationContent {
+    token: String,
+    friend_id: String,
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+struct GetMessageContent {
+    token: String,
+}
+
+#[derive(Deserialize, Default)]
+struct ListFriendsContent {
+    token: String,
+}
+
+#[derive(Deserialize, Default)]
+struct RemoveFriendContent {
+    token: String,
+    friend_id: String,
+}
+
+#[derive(Deserialize, Default)]
+struct BlockUserContent {
+    token: String,
+    blocked_id: String,
+}
+
+#[derive(Deserialize, Default)]
+struct ReportMessageContent {
+    token: String,
+    message_id: String,
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Deserialize, Default)]
+struct ModerationQueueContent {
+    moderator_key: String,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+struct HideMessageContent {
+    moderator_key: String,
+    message_id: String,
+}
+
+#[derive(Deserialize, Default)]
+struct SuspendUserContent {
+    moderator_key: String,
+    user_id: String,
+    // False lifts the suspension
+    suspended: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+struct DismissReportContent {
+    moderator_key: String,
+    report_id: String,
+}
+
+#[derive(Deserialize, Default)]
+struct AccountContent {
+    username: String,
+    password: String,
+}
+
+fn parse_content<T: DeserializeOwned>(command: &str, content: Value) -> Result<T> {
+    serde_json::from_value(content)
+        .map_err(|e| MessageError::BadRequest(format!("invalid content for {}: {}", command, e)))
+}
+
+// Runs one `{"command": ..., "content": {...}}` payload, however it arrived.
+// Apart from creating an account, logging in and the moderator commands,
+// content carries the `token` from login and acts as that user.
+pub async fn run(
+    payload_string: &str,
+    store: &dyn MessageStore,
+    moderation: &Moderation,
+    tokens: &Tokens,
+) -> Result<String> {
+    let payload: Value = serde_json::from_str(payload_string)
+        .map_err(|e| MessageError::BadRequest(format!("payload is not valid JSON: {}", e)))?;
+    let command = payload["command"]
+        .as_str()
+        .ok_or_else(|| MessageError::BadRequest("'command' is missing or not a string".to_string()))?;
+    let content = payload["content"].clone();
+    match command {
+        "createAccount" => {
+            let content: AccountContent = parse_content(command, content)?;
+            auth::create_account(store, tokens, &content.username, &content.password).await
+        }
+        "login" => {
+            let content: AccountContent = parse_content(command, content)?;
+            auth::login(store, tokens, &content.username, &content.password).await
+        }
+        "getMessage" => {
+            let content: GetMessageContent = parse_content(command, content)?;
+            let user_id = tokens.verify(&content.token)?;
+            messages::get_message(store, &user_id).await
+        }
+        "writeMessage" => {
+            let content: WriteMessageContent = parse_content(command, content)?;
+            let user_id = tokens.verify(&content.token)?;
+            messages::write_message(
+                store,
+                moderation,
+                &user_id,
+                &content.message,
+                content.recipient_id.as_deref(),
+                content.reply_to.as_deref(),
+            )
+            .await?;
+            Ok("Written!".to_string())
+        }
+        "getConversation" => {
+            let content: GetConversationContent = parse_content(command, content)?;
+            let user_id = tokens.verify(&content.token)?;
+            messages::get_conversation(
+                store,
+                &user_id,
+                &content.friend_id,
+                content.after.as_deref(),
+                content.limit,
+            )
+            .await
+        }
+        "markRead" => {
+            let content: MarkReadContent = parse_content(command, content)?;
+            let user_id = tokens.verify(&content.token)?;
+            messages::mark_read(store, &user_id, &content.message_id).await?;
+            Ok("Read!".to_string())
+        }
+        "listFriends" => {
+            let content: ListFriendsContent = parse_content(command, content)?;
+            let user_id = tokens.verify(&content.token)?;
+            messages::list_friends(store, &user_id).await
+        }
+        "removeFriend" => {
+            let content: RemoveFriendContent = parse_content(command, content)?;
+            let user_id = tokens.verify(&content.token)?;
+            messages::remove_friend(store, &user_id, &content.friend_id).await?;
+            Ok("Removed!".to_string())
+        }
+        "blockUser" => {
+            let content: BlockUserContent = parse_content(command, content)?;
+            let user_id = tokens.verify(&content.token)?;
+            messages::block_user(store, &user_id, &content.blocked_id).await?;
+            Ok("Blocked!".to_string())
+        }
+        "reportMessage" => {
+            let content: ReportMessageContent = parse_content(command, content)?;
+            let user_id = tokens.verify(&content.token)?;
+            moderation::report_message(store, &user_id, &content.message_id, &content.reason).await?;
+            Ok("Reported!".to_string())
+        }
+        "moderationQueue" => {
+            let content: ModerationQueueContent = parse_content(command, content)?;
+            moderation.moderator(&content.moderator_key)?;
+            moderation::moderation_queue(store, content.limit.unwrap_or(20)).await
+        }
+        "hideMessage" => {
+            let content: HideMessageContent = parse_content(command, content)?;
+            let moderator = moderation.moderator(&content.moderator_key)?;
+            moderation::hide_message(store, &content.message_id).await?;
+            println!("{} hid message {}", moderator, content.message_id);
+            Ok("Hidden!".to_string())
+        }
+        "suspendUser" => {
+            let content: SuspendUserContent = parse_content(command, content)?;
+            let moderator = moderation.moderator(&content.moderator_key)?;
+            let suspended = content.suspended.unwrap_or(true);
+            moderation::suspend_user(store, &content.user_id, suspended).await?;
+            println!("{} set suspended={} on {}", moderator, suspended, content.user_id);
+            Ok(if suspended { "Suspended!" } else { "Reinstated!" }.to_string())
+        }
+        "dismissReport" => {
+            let content: DismissReportContent = parse_content(command, content)?;
+            let moderator = moderation.moderator(&content.moderator_key)?;
+            moderation::dismiss_report(store, &content.report_id).await?;
+            println!("{} dismissed report {}", moderator, content.report_id);
+            Ok("Dismissed!".to_string())
+        }
+        _ => Err(MessageError::BadRequest(format!("unknown command {}", command))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::store::{MemoryStore, SqliteStore};
+
+    const MODERATOR_KEY: &str = "moderator-key";
+
+    // Runs commands against one backend the way a client would
+    struct Client {
+        store: Box<dyn MessageStore>,
+        moderation: Moderation,
+        tokens: Tokens,
+    }
+
+    impl Client {
+        async fn run(&self, command: &str, content: Value) -> Result<String> {
+            let payload = json!({"command": command, "content": content}).to_string();
+            run(&payload, self.store.as_ref(), &self.moderation, &self.tokens).await
+        }
+
+        // The user id and token of a new account
+        async fn sign_up(&self, username: &str) -> (String, String) {
+            let session = self
+                .run(
+                    "createAccount",
+                    json!({"username": username, "password": "correct horse"}),
+                )
+                .await
+                .unwrap();
+            let session: Value = serde_json::from_str(&session).unwrap();
+            (
+                session["user_id"].as_str().unwrap().to_string(),
+                session["token"].as_str().unwrap().to_string(),
+            )
+        }
+
+        async fn open_message_id(&self, author_id: &str, reader_id: &str) -> String {
+            self.store.unread_messages(author_id, reader_id).await.unwrap()[0]
+                .message_id
+                .clone()
+        }
+    }
+
+    fn clients() -> Vec<Client> {
+        let stores: Vec<Box<dyn MessageStore>> = vec![
+            Box::new(MemoryStore::new()),
+            Box::new(SqliteStore::open(":memory:").unwrap()),
+        ];
+        stores
+            .into_iter()
+            .map(|store| Client {
+                store,
+                moderation: Moderation::with_moderators(&[("mod", MODERATOR_KEY)]),
+                tokens: Tokens::new(b"0123456789abcdef0123456789abcdef".to_vec()).unwrap(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn accounts_log_in_with_the_right_password() {
+        for client in clients() {
+            let (_, token) = client.sign_up("ann").await;
+            let taken = client
+                .run("createAccount", json!({"username": "ann", "password": "another one"}))
+                .await;
+            assert!(matches!(taken, Err(MessageError::Conflict(_))));
+
+            let wrong = client
+                .run("login", json!({"username": "ann", "password": "wrong horse"}))
+                .await;
+            assert!(matches!(wrong, Err(MessageError::Unauthorized(_))));
+            let unknown = client
+                .run("login", json!({"username": "bob", "password": "correct horse"}))
+                .await;
+            assert!(matches!(unknown, Err(MessageError::Unauthorized(_))));
+            assert!(client
+                .run("login", json!({"username": "ann", "password": "correct horse"}))
+                .await
+                .is_ok());
+
+            let forged = format!("{}0", token);
+            let rejected = client.run("getMessage", json!({"token": forged})).await;
+            assert!(matches!(rejected, Err(MessageError::Unauthorized(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn open_messages_match_strangers_and_then_go_between_friends() {
+        for client in clients() {
+            let (ann_id, ann) = client.sign_up("ann").await;
+            let (bob_id, bob) = client.sign_up("bob").await;
+
+            let nobody = client.run("getMessage", json!({"token": bob})).await;
+            assert!(matches!(nobody, Err(MessageError::NotFound(_))));
+            let not_friends = client
+                .run(
+                    "writeMessage",
+                    json!({"token": ann, "message": "hi", "recipient_id": bob_id}),
+                )
+                .await;
+            assert!(matches!(not_friends, Err(MessageError::NotFound(_))));
+
+            client
+                .run("writeMessage", json!({"token": ann, "message": "hello stranger"}))
+                .await
+                .unwrap();
+            assert_eq!(
+                client.run("getMessage", json!({"token": bob})).await.unwrap(),
+                "hello stranger"
+            );
+            // Read, so there is nothing left for bob
+            let read = client.run("getMessage", json!({"token": bob})).await;
+            assert!(matches!(read, Err(MessageError::NotFound(_))));
+
+            // Matching made them friends
+            client
+                .run("writeMessage", json!({"token": ann, "message": "hello again"}))
+                .await
+                .unwrap();
+            assert_eq!(
+                client.run("getMessage", json!({"token": bob})).await.unwrap(),
+                "hello again"
+            );
+            client
+                .run(
+                    "writeMessage",
+                    json!({"token": bob, "message": "hi ann", "recipient_id": ann_id}),
+                )
+                .await
+                .unwrap();
+            let conversation = client
+                .run("getConversation", json!({"token": ann, "friend_id": bob_id}))
+                .await
+                .unwrap();
+            let conversation: Value = serde_json::from_str(&conversation).unwrap();
+            let message_id = conversation["messages"][0]["message_id"].as_str().unwrap().to_string();
+
+            assert!(client
+                .run("markRead", json!({"token": ann, "message_id": message_id}))
+                .await
+                .is_ok());
+            // Only the recipient can read an addressed message
+            let (_, cat) = client.sign_up("cat").await;
+            let not_theirs = client
+                .run("markRead", json!({"token": cat, "message_id": message_id}))
+                .await;
+            assert!(matches!(not_theirs, Err(MessageError::NotFound(_))));
+            let missing = client
+                .run("markRead", json!({"token": ann, "message_id": "missing"}))
+                .await;
+            assert!(matches!(missing, Err(MessageError::NotFound(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn blocking_ends_the_friendship_and_the_matching() {
+        for client in clients() {
+            let (ann_id, ann) = client.sign_up("ann").await;
+            let (bob_id, bob) = client.sign_up("bob").await;
+            client
+                .run("writeMessage", json!({"token": ann, "message": "hello stranger"}))
+                .await
+                .unwrap();
+            client.run("getMessage", json!({"token": bob})).await.unwrap();
+
+            client
+                .run("blockUser", json!({"token": bob, "blocked_id": ann_id}))
+                .await
+                .unwrap();
+            let friends = client.run("listFriends", json!({"token": bob})).await.unwrap();
+            assert_eq!(friends, "[]");
+            client
+                .run("writeMessage", json!({"token": ann, "message": "please"}))
+                .await
+                .unwrap();
+            let blocked = client.run("getMessage", json!({"token": bob})).await;
+            assert!(matches!(blocked, Err(MessageError::NotFound(_))));
+
+            let themselves = client
+                .run("blockUser", json!({"token": bob, "blocked_id": bob_id}))
+                .await;
+            assert!(matches!(themselves, Err(MessageError::BadRequest(_))));
+            let unknown = client
+                .run("blockUser", json!({"token": bob, "blocked_id": "nobody"}))
+                .await;
+            assert!(matches!(unknown, Err(MessageError::NotFound(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_reach_the_moderators() {
+        for client in clients() {
+            let (ann_id, ann) = client.sign_up("ann").await;
+            let (bob_id, bob) = client.sign_up("bob").await;
+            client
+                .run("writeMessage", json!({"token": ann, "message": "buy my stuff"}))
+                .await
+                .unwrap();
+            let message_id = client.open_message_id(&ann_id, &bob_id).await;
+
+            let own = client
+                .run("reportMessage", json!({"token": ann, "message_id": message_id}))
+                .await;
+            assert!(matches!(own, Err(MessageError::NotFound(_))));
+            client
+                .run(
+                    "reportMessage",
+                    json!({"token": bob, "message_id": message_id, "reason": "spam"}),
+                )
+                .await
+                .unwrap();
+
+            let not_a_moderator = client.run("moderationQueue", json!({"moderator_key": "guess"})).await;
+            assert!(matches!(not_a_moderator, Err(MessageError::Forbidden(_))));
+            let queue = client
+                .run("moderationQueue", json!({"moderator_key": MODERATOR_KEY}))
+                .await
+                .unwrap();
+            let queue: Value = serde_json::from_str(&queue).unwrap();
+            assert_eq!(queue.as_array().unwrap().len(), 1);
+
+            client
+                .run(
+                    "suspendUser",
+                    json!({"moderator_key": MODERATOR_KEY, "user_id": ann_id}),
+                )
+                .await
+                .unwrap();
+            let suspended = client
+                .run("writeMessage", json!({"token": ann, "message": "one more"}))
+                .await;
+            assert!(matches!(suspended, Err(MessageError::Forbidden(_))));
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use serde_json::json;
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, MessageError>;
+
+// Everything that can go wrong while running a command, each mapping to
+// the HTTP status the caller sees
+#[derive(Debug)]
+pub enum MessageError {
+    // The payload or its content was malformed
+    BadRequest(String),
+    // The caller's token or password is missing, wrong or expired
+    Unauthorized(String),
+    // The caller isn't allowed to do this
+    Forbidden(String),
+    NotFound(String),
+    // The request clashes with something that already exists
+    Conflict(String),
+    // The caller is sending too much, too quickly
+    TooManyRequests(String),
+    // The store couldn't be reached or misbehaved
+    Backend(String),
+}
+
+impl MessageError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            MessageError::BadRequest(_) => 400,
+            MessageError::Unauthorized(_) => 401,
+            MessageError::Forbidden(_) => 403,
+            MessageError::NotFound(_) => 404,
+            MessageError::Conflict(_) => 409,
+            MessageError::TooManyRequests(_) => 429,
+            MessageError::Backend(_) => 500,
+        }
+    }
+
+    // Backend details are logged rather than handed to the caller
+    pub fn to_json(&self) -> String {
+        let (error, message) = match self {
+            MessageError::BadRequest(message) => ("bad_request", message.as_str()),
+            MessageError::Unauthorized(message) => ("unauthorized", message.as_str()),
+            MessageError::Forbidden(message) => ("forbidden", message.as_str()),
+            MessageError::NotFound(message) => ("not_found", message.as_str()),
+            MessageError::Conflict(message) => ("conflict", message.as_str()),
+            MessageError::TooManyRequests(message) => ("too_many_requests", message.as_str()),
+            MessageError::Backend(_) => ("backend", "the message store failed, try again later"),
+        };
+        json!({"error": error, "message": message}).to_string()
+    }
+
+    pub fn backend(error: impl fmt::Debug) -> Self {
+        MessageError::Backend(format!("{:?}", error))
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageError::BadRequest(message) => write!(f, "bad request: {}", message),
+            MessageError::Unauthorized(message) => write!(f, "unauthorized: {}", message),
+            MessageError::Forbidden(message) => write!(f, "forbidden: {}", message),
+            MessageError::NotFound(message) => write!(f, "not found: {}", message),
+            MessageError::Conflict(message) => write!(f, "conflict: {}", message),
+            MessageError::TooManyRequests(message) => write!(f, "too many requests: {}", message),
+            MessageError::Backend(message) => write!(f, "backend error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl From<rusqlite::Error> for MessageError {
+    fn from(error: rusqlite::Error) -> Self {
+        match error {
+            rusqlite::Error::SqliteFailure(e, ref message) if e.code == rusqlite::ErrorCode::ConstraintViolation => {
+                MessageError::Conflict(message.clone().unwrap_or_else(|| "constraint violated".to_string()))
+            }
+            _ => MessageError::backend(error),
+        }
+    }
+}
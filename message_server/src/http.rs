@@ -0,0 +1,71 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use warp::{
+    http::StatusCode,
+    hyper::body::Bytes,
+    reply::{with_header, with_status, Response},
+    Filter, Rejection, Reply,
+};
+
+use crate::auth::Tokens;
+use crate::commands;
+use crate::error::MessageError;
+use crate::moderation::Moderation;
+use crate::store::MessageStore;
+
+// Payloads are a command and a short message, anything bigger is a mistake
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+// Serves the same commands as the Lambda, POSTed to `/` with the same
+// envelope, for running next to battista_server without AWS
+pub async fn serve(addr: SocketAddr, store: Arc<dyn MessageStore>, moderation: Arc<Moderation>, tokens: Arc<Tokens>) {
+    let command_route = warp::path::end()
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and(with_store(store))
+        .and(with_moderation(moderation))
+        .and(with_tokens(tokens))
+        .and_then(command_handler);
+
+    let cors = warp::cors()
+        .allow_any_origin()
+        .allow_headers(vec!["Content-Type"])
+        .allow_methods(vec!["POST", "OPTIONS"]);
+
+    println!("Serving messages on {}", addr);
+    warp::serve(command_route.with(cors)).run(addr).await;
+}
+
+async fn command_handler(
+    body: Bytes,
+    store: Arc<dyn MessageStore>,
+    moderation: Arc<Moderation>,
+    tokens: Arc<Tokens>,
+) -> Result<Response, Rejection> {
+    let result = match std::str::from_utf8(&body) {
+        Ok(payload) => commands::run(payload, store.as_ref(), &moderation, &tokens).await,
+        Err(_) => Err(MessageError::BadRequest("request body is not text".to_string())),
+    };
+    match result {
+        Ok(response) => Ok(with_status(response, StatusCode::OK).into_response()),
+        Err(error) => {
+            println!("Error: {}", error);
+            let status = StatusCode::from_u16(error.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            Ok(with_status(with_header(error.to_json(), "content-type", "application/json"), status).into_response())
+        }
+    }
+}
+
+fn with_store(store: Arc<dyn MessageStore>) -> impl Filter<Extract = (Arc<dyn MessageStore>,), Error = Infallible> + Clone {
+    warp::any().map(move || store.clone())
+}
+
+fn with_moderation(moderation: Arc<Moderation>) -> impl Filter<Extract = (Arc<Moderation>,), Error = Infallible> + Clone {
+    warp::any().map(move || moderation.clone())
+}
+
+fn with_tokens(tokens: Arc<Tokens>) -> impl Filter<Extract = (Arc<Tokens>,), Error = Infallible> + Clone {
+    warp::any().map(move || tokens.clone())
+}
@@ -1,64 +1,79 @@
 use lambda_http::{
     handler,
     lambda_runtime::{self, Context},
-    Body, IntoResponse, Request, RequestExt, Response,
+    Body, Request, Response,
 };
-use serde::Deserialize;
-use serde_json::Value;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
-mod messages;
-
-#[derive(Deserialize, Default)]
-struct WriteMessageContent {
-    user_id: String,
-    message: String
-}
+use auth::Tokens;
+use error::MessageError;
+use moderation::Moderation;
+use store::MessageStore;
 
-#[derive(Deserialize, Default)]
-struct GetMessageContent {
-    user_id: String,
-}
+mod auth;
+mod commands;
+mod error;
+mod http;
+mod matchmaking;
+mod messages;
+mod moderation;
+mod store;
 
-#[derive(Deserialize, Default)]
-struct LoginContent {
-    username: String
-}
+// Set to an address such as 127.0.0.1:9000 to serve plain HTTP instead of
+// running as a Lambda, same as passing `--http <addr>`
+const HTTP_ADDR_VAR: &str = "MESSAGE_SERVER_HTTP";
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    lambda_runtime::run(handler(write_message)).await?;
+    let store: Arc<dyn MessageStore> = Arc::from(store::from_env()?);
+    let moderation = Arc::new(Moderation::from_env()?);
+    let tokens = Arc::new(Tokens::from_env()?);
+    if let Some(addr) = http_addr()? {
+        http::serve(addr, store, moderation, tokens).await;
+        return Ok(());
+    }
+    lambda_runtime::run(handler(move |request, context| {
+        write_message(request, context, store.clone(), moderation.clone(), tokens.clone())
+    })).await?;
     Ok(())
 }
 
-async fn write_message(request: Request, _: Context) -> Result<Response<Body>, Error> {
-    let payload_string = match request.body() {
-        Body::Text(string) => string,
-        _ => panic!("Request body is not text!")
+fn http_addr() -> Result<Option<SocketAddr>, Error> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let addr = match args.as_slice() {
+        [] => env::var(HTTP_ADDR_VAR).ok(),
+        [flag, addr] if flag == "--http" => Some(addr.clone()),
+        _ => return Err("usage: bootstrap [--http <addr>]".into()),
     };
-    let payload: Value = serde_json::from_str(payload_string)?;
-    let command: &str = payload["command"].as_str().expect("'command' is missing or not a string!");
-    let content = payload["content"].clone();
-    let response: String = match command {
-        "login" => {
-            let content: LoginContent = serde_json::from_value(content).unwrap();
-            let user_id = messages::get_or_create_user(&content.username).await;
-            format!("{}", user_id)
-        }
-        "getMessage" => {
-            let content: GetMessageContent = serde_json::from_value(content).unwrap();
-            let message = messages::get_message(&content.user_id).await;
-            message
-        }
-        "writeMessage" => {
-            let content: WriteMessageContent = serde_json::from_value(content).unwrap();
-            messages::write_message(&content.user_id, &content.message).await;
-            "Written!".to_string()
-        },
-        _ => String::from("No 'command' provided in the message payload!"),
+    match addr {
+        Some(addr) => Ok(Some(addr.parse().map_err(|e| format!("invalid address {}: {}", addr, e))?)),
+        None => Ok(None),
+    }
+}
+
+async fn write_message(
+    request: Request,
+    _: Context,
+    store: Arc<dyn MessageStore>,
+    moderation: Arc<Moderation>,
+    tokens: Arc<Tokens>,
+) -> Result<Response<Body>, Error> {
+    let result = match request.body() {
+        Body::Text(payload_string) => commands::run(payload_string, store.as_ref(), &moderation, &tokens).await,
+        _ => Err(MessageError::BadRequest("request body is not text".to_string())),
     };
-    Ok(Response::new(
-        format!("{}", response).into(),
-    ))
+    match result {
+        Ok(response) => Ok(Response::new(response.into())),
+        Err(error) => {
+            println!("Error: {}", error);
+            Ok(Response::builder()
+                .status(error.status_code())
+                .header("content-type", "application/json")
+                .body(error.to_json().into())?)
+        }
+    }
 }
@@ -0,0 +1,32 @@
+use crate::error::Result;
+use crate::store::{Message, MessageStore};
+
+// How many authors with unread messages are looked at per request
+const MAX_CANDIDATES: usize = 20;
+
+pub enum Match {
+    // A new partner and the message of theirs to hand over
+    Partner(Message),
+    NobodyAvailable,
+}
+
+// Picks a new partner for a user whose friends have nothing unread. The store
+// only offers authors with open messages the user hasn't read who aren't
+// already matched, so this looks at a bounded number of them instead of
+// probing random users. The oldest of their unread messages wins.
+pub async fn find_partner(store: &dyn MessageStore, user_id: &str) -> Result<Match> {
+    let mut selected: Option<Message> = None;
+    for candidate in store.match_candidates(user_id, MAX_CANDIDATES).await? {
+        // The candidate's messages may all have been read since they were listed
+        let oldest = store.unread_messages(&candidate, user_id).await?.into_iter().next();
+        if let Some(oldest) = oldest {
+            if selected.as_ref().map_or(true, |selected| oldest.delivery_order() < selected.delivery_order()) {
+                selected = Some(oldest);
+            }
+        }
+    }
+    Ok(match selected {
+        Some(message) => Match::Partner(message),
+        None => Match::NobodyAvailable,
+    })
+}
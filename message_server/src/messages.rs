@@ -1,419 +1,234 @@
-use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
-use rand;
-use rand::seq::SliceRandom;
+use serde_json::{json, Value};
 
-use rusoto_core::Region;
-use rusoto_dynamodb::{
-    AttributeValue, DynamoDb, DynamoDbClient, GetItemInput, PutItemInput, QueryInput, ScanInput,
-    UpdateItemInput,
-};
+use crate::error::{MessageError, Result};
+use crate::matchmaking::{self, Match};
+use crate::moderation::Moderation;
+use crate::store::{Message, MessageStore};
 
-pub async fn get_or_create_user(user_name: &String) -> String {
-    let mut user_id;
-    user_id = get_user(&user_name).await;
-    if user_id == "" {
-        user_id = create_user(&user_name).await;
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+async fn require_user(store: &dyn MessageStore, user_id: &str) -> Result<()> {
+    match store.user(user_id).await? {
+        Some(_) => Ok(()),
+        None => Err(MessageError::NotFound(format!("no user with id {}", user_id))),
     }
-    return user_id;
 }
 
-async fn get_user(user_name: &String) -> String {
-    let dynamo_client = DynamoDbClient::new(Region::UsWest2);
-    let expression_attribute_values: HashMap<String, AttributeValue> = [(
-        ":user_name".to_string(),
-        AttributeValue {
-            s: Some(user_name.to_string()),
-            ..Default::default()
-        },
-    )]
-    .iter()
-    .cloned()
-    .collect();
-    let query_input = QueryInput {
-        expression_attribute_values: Some(expression_attribute_values),
-        key_condition_expression: Some("user_name = :user_name".to_string()),
-        index_name: Some(String::from("user_name_index")),
-        table_name: String::from("users"),
-        ..Default::default()
-    };
-    match dynamo_client.query(query_input).await {
-        Ok(output) => match output.items {
-            Some(items) => {
-                if items.len() > 1 {
-                    panic!("More than one user with same name!");
-                } else if items.len() == 1 {
-                    return items[0]["user_id"].s.as_ref().unwrap().to_string();
-                } else {
-                    return "".to_string();
-                }
-            }
-            None => return "".to_string(),
-        },
-        Err(error) => {
-            println!("Error: {:?}", error);
-            return "".to_string();
-        }
-    };
+// Suspended users can still read, but not write or report
+pub async fn require_active_user(store: &dyn MessageStore, user_id: &str) -> Result<()> {
+    match store.user(user_id).await? {
+        Some(user) if user.suspended => Err(MessageError::Forbidden(format!("{} is suspended", user_id))),
+        Some(_) => Ok(()),
+        None => Err(MessageError::NotFound(format!("no user with id {}", user_id))),
+    }
 }
 
-async fn create_user(user_name: &String) -> String {
-    let dynamo_client = DynamoDbClient::new(Region::UsWest2);
-    let random_user_id = Uuid::new_v4();
-    let item: HashMap<String, AttributeValue> = [
-        (
-            String::from("user_id"),
-            AttributeValue {
-                s: Some(random_user_id.to_string()),
-                ..Default::default()
-            },
-        ),
-        (
-            String::from("user_name"),
-            AttributeValue {
-                s: Some(user_name.to_string()),
-                ..Default::default()
-            },
-        ),
-        (
-            String::from("relationships"),
-            AttributeValue {
-                l: Some(vec![]),
-                ..Default::default()
-            },
-        ),
-    ]
-    .iter()
-    .cloned()
-    .collect();
-    let put_input = PutItemInput {
-        item: item,
-        table_name: String::from("users"),
-        ..Default::default()
-    };
-    match dynamo_client.put_item(put_input).await {
-        Ok(_) => {
-            println!("User created.");
-            return random_user_id.to_string();
-        }
-        Err(error) => {
-            println!("Error: {:?}", error);
-            return "".to_string();
-        }
+async fn require_friend(store: &dyn MessageStore, user_id: &str, friend_id: &str) -> Result<()> {
+    if !store.relationships(user_id).await?.iter().any(|friend| friend == friend_id) {
+        return Err(MessageError::NotFound(format!("{} is not a friend of {}", friend_id, user_id)));
     }
+    Ok(())
 }
 
-pub async fn write_message(user_id: &String, message: &String) {
-    let dynamo_client = DynamoDbClient::new(Region::UsWest2);
-    let message_id = Uuid::new_v4();
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
 
-    // Update the player's list of available messages
-    let item: HashMap<String, AttributeValue> = [
-        (
-            String::from("user_id"),
-            AttributeValue {
-                s: Some(String::from(user_id)),
-                ..Default::default()
-            },
-        ),
-        (
-            String::from("message_id"),
-            AttributeValue {
-                s: Some(format!("{}", message_id)),
-                ..Default::default()
-            },
-        ),
-        (
-            String::from("message"),
-            AttributeValue {
-                s: Some(message.to_string()),
-                ..Default::default()
-            },
-        ),
-        (
-            String::from("unread"),
-            AttributeValue {
-                s: Some(String::from("t")),
-                ..Default::default()
-            },
-        ),
-    ]
-    .iter()
-    .cloned()
-    .collect();
-    let put_input = PutItemInput {
-        item: item,
-        table_name: String::from("messages"),
-        ..Default::default()
-    };
-    match dynamo_client.put_item(put_input).await {
-        Ok(_) => println!("Message sent!"),
-        Err(error) => {
-            println!("Error: {:?}", error);
+// Without a recipient or reply the message is open, and goes to whoever gets
+// matched with the author. Addressed messages can only go to friends.
+pub async fn write_message(
+    store: &dyn MessageStore,
+    moderation: &Moderation,
+    user_id: &str,
+    message: &str,
+    recipient_id: Option<&str>,
+    reply_to: Option<&str>,
+) -> Result<()> {
+    require_active_user(store, user_id).await?;
+    moderation.check_text(message)?;
+    moderation.check_rate(store, user_id).await?;
+    let message_id = Uuid::new_v4().to_string();
+    let (recipient_id, thread_id) = match reply_to {
+        Some(reply_to) => {
+            let unknown = || MessageError::NotFound(format!("no message with id {}", reply_to));
+            let original = match store.message(reply_to).await? {
+                Some(original) if !original.hidden => original,
+                _ => return Err(unknown()),
+            };
+            // A reply goes back to the other user in the thread. Answering an
+            // open message starts a new thread with its author.
+            let (other, thread_id) = match &original.recipient_id {
+                Some(to) if original.user_id == user_id => (to.clone(), original.thread_id),
+                Some(to) if to == user_id => (original.user_id, original.thread_id),
+                Some(_) => return Err(unknown()),
+                None => (original.user_id, message_id.clone()),
+            };
+            if recipient_id.is_some_and(|recipient_id| recipient_id != other) {
+                return Err(MessageError::BadRequest(format!("a reply to {} can only go to {}", reply_to, other)));
+            }
+            (Some(other), thread_id)
         }
+        None => (recipient_id.map(str::to_string), message_id.clone()),
+    };
+    if let Some(recipient_id) = &recipient_id {
+        require_friend(store, user_id, recipient_id).await?;
     }
+    store
+        .put_message(Message {
+            user_id: user_id.to_string(),
+            message_id,
+            message: message.to_string(),
+            recipient_id,
+            reply_to: reply_to.map(str::to_string),
+            thread_id,
+            created_at: now_millis(),
+            hidden: false,
+        })
+        .await
 }
 
-pub async fn get_message(user_id: &String) -> String {
-    let mut message = get_friend_message(user_id).await;
-    println!("Found a message: {}", message);
-    if message == "" {
-        message = create_new_relationship(user_id).await;
+// A page of the messages between the user and a friend, oldest first, as
+// `{"messages": [...], "next": <message_id or null>}`. Passing `next` back as
+// `after` gets the following page.
+pub async fn get_conversation(
+    store: &dyn MessageStore,
+    user_id: &str,
+    friend_id: &str,
+    after: Option<&str>,
+    limit: Option<usize>,
+) -> Result<String> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(MessageError::BadRequest(format!("limit should be between 1 and {}", MAX_PAGE_SIZE)));
     }
-    return message;
-}
-
-async fn get_friend_message(user_id: &String) -> String {
-    let mut selected_message_id = "".to_string();
-    let mut selected_user_id = "".to_string();
-    let mut selected_message_content = "".to_string();
-
-    let dynamo_client = DynamoDbClient::new(Region::UsWest2);
-
-    // Get all friends
-    let mut relationships = vec![];
-    let key: HashMap<String, AttributeValue> = [(
-        "user_id".to_string(),
-        AttributeValue {
-            s: Some(user_id.to_string()),
-            ..Default::default()
+    require_user(store, user_id).await?;
+    require_user(store, friend_id).await?;
+    let conversation = store.conversation(user_id, friend_id).await?;
+    let start = match after {
+        Some(after) => match conversation.iter().position(|message| message.message_id == after) {
+            Some(position) => position + 1,
+            None => return Err(MessageError::BadRequest(format!("{} is not part of this conversation", after))),
         },
-    )]
-    .iter()
-    .cloned()
-    .collect();
-    let get_item_input = GetItemInput {
-        key: key,
-        table_name: String::from("users"),
-        ..Default::default()
+        None => 0,
     };
-    match dynamo_client.get_item(get_item_input).await {
-        Ok(output) => match output.item {
-            Some(item) => {
-                let item_relationships = item["relationships"].l.as_ref().unwrap();
-                for r in item_relationships.iter() {
-                    let relationship = r.m.as_ref().unwrap();
-                    relationships.push(relationship["user_id"].s.as_ref().unwrap().clone());
-                }
-            }
-            None => println!("Something went wrong"),
-        },
-        Err(error) => {
-            println!("Error: {:?}", error);
-        }
+    let page: Vec<&Message> = conversation.iter().skip(start).take(limit).collect();
+    let next = match page.last() {
+        Some(last) if start + page.len() < conversation.len() => Some(last.message_id.clone()),
+        _ => None,
     };
+    let messages: Vec<Value> = page
+        .iter()
+        .map(|message| {
+            json!({
+                "message_id": message.message_id,
+                "user_id": message.user_id,
+                "recipient_id": message.recipient_id,
+                "reply_to": message.reply_to,
+                "thread_id": message.thread_id,
+                "message": message.message,
+                "created_at": message.created_at,
+            })
+        })
+        .collect();
+    Ok(json!({"messages": messages, "next": next}).to_string())
+}
 
-    if relationships.len() == 0 {
-        return selected_message_content;
+pub async fn get_message(store: &dyn MessageStore, user_id: &str) -> Result<String> {
+    require_user(store, user_id).await?;
+    if let Some(message) = get_friend_message(store, user_id).await? {
+        println!("Found a message: {}", message);
+        return Ok(message);
     }
+    create_new_relationship(store, user_id).await
+}
 
-    // Get a random message
-    relationships.shuffle(&mut rand::thread_rng());
-    for chosen_user in relationships {
-        // Get all unread messages from selected user
-        let expression_attribute_values: HashMap<String, AttributeValue> = [(
-            ":user_id".to_string(),
-            AttributeValue {
-                s: Some(chosen_user.to_string()),
-                ..Default::default()
-            },
-        )]
-        .iter()
-        .cloned()
-        .collect();
-        let query_input = QueryInput {
-            expression_attribute_values: Some(expression_attribute_values),
-            key_condition_expression: Some("user_id = :user_id".to_string()),
-            index_name: Some(String::from("GSI1")),
-            table_name: String::from("messages"),
-            ..Default::default()
-        };
-        match dynamo_client.query(query_input).await {
-            Ok(output) => match output.items {
-                Some(items) => match items.choose(&mut rand::thread_rng()) {
-                    Some(selected) => {
-                        selected_message_content = selected["message"].s.as_ref().unwrap().to_string();
-                        selected_user_id = selected["user_id"].s.as_ref().unwrap().clone();
-                        selected_message_id = selected["message_id"].s.as_ref().unwrap().clone();
-                    }
-                    None => println!("No messages available"),
-                },
-                None => println!("Something went wrong"),
-            },
-            Err(error) => {
-                println!("Error: {:?}", error);
+async fn get_friend_message(store: &dyn MessageStore, user_id: &str) -> Result<Option<String>> {
+    // The oldest open message from any friend that this user hasn't read
+    let mut selected: Option<Message> = None;
+    for friend_id in store.relationships(user_id).await? {
+        let oldest = store.unread_messages(&friend_id, user_id).await?.into_iter().next();
+        if let Some(oldest) = oldest {
+            if selected.as_ref().map_or(true, |selected| oldest.delivery_order() < selected.delivery_order()) {
+                selected = Some(oldest);
             }
-        };
-
-        if selected_message_id == "" || selected_user_id == "" {
+        }
+    }
+    match selected {
+        Some(selected) => {
+            store.mark_read(user_id, &selected.message_id).await?;
+            Ok(Some(selected.message))
+        }
+        None => {
             println!("No valid message found!");
-            return selected_message_content;
+            Ok(None)
         }
+    }
+}
 
-        // Mark a message as read
-        let key: HashMap<String, AttributeValue> = [
-            (
-                String::from("user_id"),
-                AttributeValue {
-                    s: Some(selected_user_id.to_string()),
-                    ..Default::default()
-                },
-            ),
-            (
-                String::from("message_id"),
-                AttributeValue {
-                    s: Some(selected_message_id.to_string()),
-                    ..Default::default()
-                },
-            ),
-        ]
-        .iter()
-        .cloned()
-        .collect();
-        let update_input = UpdateItemInput {
-            key: key,
-            update_expression: Some("REMOVE unread".to_string()),
-            table_name: String::from("messages"),
-            ..Default::default()
-        };
-        match dynamo_client.update_item(update_input).await {
-            Ok(output) => println!("Read"),
-            Err(error) => {
-                println!("Error: {:?}", error);
-            }
+async fn create_new_relationship(store: &dyn MessageStore, user_id: &str) -> Result<String> {
+    // TODO: Select the partner on factors such as activity
+    match matchmaking::find_partner(store, user_id).await? {
+        Match::Partner(message) => {
+            store.add_relationship(user_id, &message.user_id).await?;
+            store.mark_read(user_id, &message.message_id).await?;
+            println!("Matched {} with {}", user_id, message.user_id);
+            Ok(message.message)
         }
+        Match::NobodyAvailable => Err(MessageError::NotFound(
+            "nobody has a message for you right now".to_string(),
+        )),
     }
-    return selected_message_content;
 }
 
-async fn create_new_relationship(user_id: &String) -> String {
-    let dynamo_client = DynamoDbClient::new(Region::UsWest2);
-    let mut selected_message_id = "".to_string();
-    let mut selected_user_id = "".to_string();
-    let mut selected_message_content = "".to_string();
-
-    // Find a random user
-    // TODO: Select random user on factors such as activity
-    // TODO: This can loop forever if there are no unread messages at all
-    while selected_message_id == "" {
-        let random_user_id = Uuid::new_v4();
-        let start_key: HashMap<String, AttributeValue> = [(
-            "user_id".to_string(),
-            AttributeValue {
-                s: Some(random_user_id.to_string()),
-                ..Default::default()
-            },
-        )]
-        .iter()
-        .cloned()
-        .collect();
-        let scan_input = ScanInput {
-            exclusive_start_key: Some(start_key),
-            table_name: "users".to_string(),
-            limit: Some(1),
-            ..Default::default()
-        };
-        match dynamo_client.scan(scan_input).await {
-            Ok(output) => match output.items {
-                Some(items) => {
-                    selected_user_id = String::from(items[0]["user_id"].s.as_ref().unwrap())
-                }
-                None => println!("NO users exist? Really?"),
-            },
-            Err(error) => {
-                println!("Error: {:?}", error);
-                return selected_message_content;
-            }
-        };
-        println!("{} random user", selected_user_id);
-
-        // Check if that player has available messages
-        let expression_attribute_values: HashMap<String, AttributeValue> = [(
-            ":user_id".to_string(),
-            AttributeValue {
-                s: Some(selected_user_id.to_string()),
-                ..Default::default()
-            },
-        )]
-        .iter()
-        .cloned()
-        .collect();
-        let query_input = QueryInput {
-            expression_attribute_values: Some(expression_attribute_values),
-            key_condition_expression: Some("user_id = :user_id".to_string()),
-            index_name: Some(String::from("GSI1")),
-            table_name: String::from("messages"),
-            ..Default::default()
-        };
-        match dynamo_client.query(query_input).await {
-            Ok(output) => match output.items {
-                Some(items) => match items.choose(&mut rand::thread_rng()) {
-                    Some(selected) => {
-                        selected_message_content = selected["message"].s.as_ref().unwrap().to_string();
-                        selected_user_id = selected["user_id"].s.as_ref().unwrap().clone();
-                        selected_message_id = selected["message_id"].s.as_ref().unwrap().clone();
-                    }
-                    None => println!("No messages available"),
-                },
-                None => println!("Something went wrong"),
-            },
-            Err(error) => {
-                println!("Error: {:?}", error);
-            }
-        };
-        // If no messages for this random user, check another random
+// The user's friends as a JSON list of `{"user_id", "user_name", "unread"}`,
+// where `unread` counts the friend's messages this user hasn't read
+pub async fn list_friends(store: &dyn MessageStore, user_id: &str) -> Result<String> {
+    require_user(store, user_id).await?;
+    let mut friends = vec![];
+    for friend_id in store.relationships(user_id).await? {
+        // Skip friends whose user has since gone away
+        if let Some(friend) = store.user(&friend_id).await? {
+            let unread = store.unread_count(&friend.user_id, user_id).await?;
+            friends.push(json!({"user_id": friend.user_id, "user_name": friend.user_name, "unread": unread}));
+        }
     }
+    Ok(Value::Array(friends).to_string())
+}
 
-    // If yes, create new relationship for both players
-    let key: HashMap<String, AttributeValue> = [(
-        String::from("user_id"),
-        AttributeValue {
-            s: Some(user_id.to_string()),
-            ..Default::default()
-        },
-    )]
-    .iter()
-    .cloned()
-    .collect();
-    let relationship_map: HashMap<String, AttributeValue> = [(
-        "user_id".to_string(),
-        AttributeValue {
-            s: Some(selected_user_id.to_string()),
-            ..Default::default()
-        },
-    )]
-    .iter()
-    .cloned()
-    .collect();
-    let expression_attribute_values: HashMap<String, AttributeValue> = [(
-        String::from(":relationship"),
-        AttributeValue {
-            l: Some(vec![AttributeValue {
-                m: Some(relationship_map),
-                ..Default::default()
-            }]),
-            ..Default::default()
+// Only the recipient of an addressed message, or anyone but the author of an
+// open one, can read it
+pub async fn mark_read(store: &dyn MessageStore, user_id: &str, message_id: &str) -> Result<()> {
+    require_user(store, user_id).await?;
+    let readable = match store.message(message_id).await? {
+        Some(message) => match &message.recipient_id {
+            Some(recipient_id) => recipient_id == user_id,
+            None => message.user_id != user_id,
         },
-    )]
-    .iter()
-    .cloned()
-    .collect();
-    let update_input = UpdateItemInput {
-        key: key,
-        expression_attribute_values: Some(expression_attribute_values),
-        update_expression: Some(
-            "SET relationships = list_append(relationships, :relationship)".to_string(),
-        ),
-        table_name: String::from("users"),
-        ..Default::default()
+        None => false,
     };
-    match dynamo_client.update_item(update_input).await {
-        Ok(output) => println!("Relationship1 created"),
-        Err(error) => {
-            println!("Error: {:?}", error);
-        }
+    if !readable {
+        return Err(MessageError::NotFound(format!("no message with id {} for {}", message_id, user_id)));
+    }
+    store.mark_read(user_id, message_id).await
+}
+
+pub async fn remove_friend(store: &dyn MessageStore, user_id: &str, friend_id: &str) -> Result<()> {
+    require_user(store, user_id).await?;
+    if !store.remove_relationship(user_id, friend_id).await? {
+        return Err(MessageError::NotFound(format!("{} is not a friend of {}", friend_id, user_id)));
     }
+    Ok(())
+}
 
-    return selected_message_content;
+pub async fn block_user(store: &dyn MessageStore, user_id: &str, blocked_id: &str) -> Result<()> {
+    if user_id == blocked_id {
+        return Err(MessageError::BadRequest("users can't block themselves".to_string()));
+    }
+    require_user(store, user_id).await?;
+    require_user(store, blocked_id).await?;
+    store.block_user(user_id, blocked_id).await
 }
@@ -0,0 +1,269 @@
+use regex::{Regex, RegexBuilder};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use uuid::Uuid;
+
+use crate::error::{MessageError, Result};
+use crate::messages::{now_millis, require_active_user};
+use crate::store::{MessageStore, Report};
+
+// Longest message, in characters
+pub const MAX_LENGTH_VAR: &str = "MESSAGE_MAX_LENGTH";
+// A file with one filter per line, either a word or phrase matched on word
+// boundaries ignoring case, or `re:` and a regular expression. Blank lines
+// and lines starting with `#` are skipped.
+pub const FILTER_FILE_VAR: &str = "MESSAGE_FILTER_FILE";
+// `<count>/<seconds>` messages per user, or `off`
+pub const RATE_LIMIT_VAR: &str = "MESSAGE_RATE_LIMIT";
+// Comma separated `name:key` pairs, e.g. `alice:s3cret,ops-bot:an0ther`
+pub const MODERATOR_KEYS_VAR: &str = "MESSAGE_MODERATOR_KEYS";
+
+const DEFAULT_MAX_LENGTH: usize = 500;
+const DEFAULT_RATE_LIMIT: RateLimit = RateLimit { count: 10, seconds: 60 };
+const MAX_REASON_LENGTH: usize = 500;
+const MAX_QUEUE_PAGE: usize = 100;
+
+#[derive(Clone, Copy, Debug)]
+struct RateLimit {
+    count: usize,
+    seconds: u64,
+}
+
+// What may be written and who may moderate. Messages are paired between
+// strangers, so everything is checked before it's stored.
+#[derive(Debug)]
+pub struct Moderation {
+    max_length: usize,
+    filters: Vec<Regex>,
+    rate_limit: Option<RateLimit>,
+    moderator_keys: HashMap<String, String>,
+}
+
+impl Moderation {
+    pub fn from_env() -> std::result::Result<Moderation, String> {
+        let max_length = match env::var(MAX_LENGTH_VAR) {
+            Ok(value) => match value.parse() {
+                Ok(max_length) if max_length > 0 => max_length,
+                _ => return Err(format!("{} should be a positive number, not {}", MAX_LENGTH_VAR, value)),
+            },
+            Err(_) => DEFAULT_MAX_LENGTH,
+        };
+        let filters = match env::var(FILTER_FILE_VAR) {
+            Ok(path) => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| format!("could not read {} at {}: {}", FILTER_FILE_VAR, path, e))?;
+                parse_filters(&contents).map_err(|e| format!("bad filter in {}: {}", path, e))?
+            }
+            Err(_) => vec![],
+        };
+        let rate_limit = match env::var(RATE_LIMIT_VAR) {
+            Ok(value) if value == "off" => None,
+            Ok(value) => Some(parse_rate_limit(&value).ok_or_else(|| {
+                format!("{} should look like <count>/<seconds> or off, not {}", RATE_LIMIT_VAR, value)
+            })?),
+            Err(_) => Some(DEFAULT_RATE_LIMIT),
+        };
+        let mut moderator_keys = HashMap::new();
+        if let Ok(value) = env::var(MODERATOR_KEYS_VAR) {
+            for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+                match pair.split_once(':') {
+                    Some((name, key)) if !name.is_empty() && !key.is_empty() => {
+                        moderator_keys.insert(name.to_string(), key.to_string());
+                    }
+                    _ => println!("Ignoring malformed entry in {}, expected name:key", MODERATOR_KEYS_VAR),
+                }
+            }
+        }
+        if moderator_keys.is_empty() {
+            println!("{} is not set, moderator commands will refuse every request", MODERATOR_KEYS_VAR);
+        }
+        Ok(Moderation {
+            max_length,
+            filters,
+            rate_limit,
+            moderator_keys,
+        })
+    }
+
+    // Defaults without a rate limit, for running commands in tests
+    #[cfg(test)]
+    pub fn with_moderators(moderator_keys: &[(&str, &str)]) -> Moderation {
+        Moderation {
+            max_length: DEFAULT_MAX_LENGTH,
+            filters: vec![],
+            rate_limit: None,
+            moderator_keys: moderator_keys
+                .iter()
+                .map(|(name, key)| (name.to_string(), key.to_string()))
+                .collect(),
+        }
+    }
+
+    // Doesn't say which filter matched, so it can't be used to probe them
+    pub fn check_text(&self, text: &str) -> Result<()> {
+        if text.trim().is_empty() {
+            return Err(MessageError::BadRequest("message is empty".to_string()));
+        }
+        if text.chars().count() > self.max_length {
+            return Err(MessageError::BadRequest(format!(
+                "message is longer than {} characters",
+                self.max_length
+            )));
+        }
+        if self.filters.iter().any(|filter| filter.is_match(text)) {
+            return Err(MessageError::BadRequest("message contains blocked content".to_string()));
+        }
+        Ok(())
+    }
+
+    // Counts what the user has stored, so it holds across Lambda instances
+    pub async fn check_rate(&self, store: &dyn MessageStore, user_id: &str) -> Result<()> {
+        let limit = match self.rate_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let since = now_millis().saturating_sub(limit.seconds * 1000);
+        if store.recent_message_count(user_id, since).await? >= limit.count {
+            return Err(MessageError::TooManyRequests(format!(
+                "at most {} messages every {} seconds",
+                limit.count, limit.seconds
+            )));
+        }
+        Ok(())
+    }
+
+    // Who the moderator key belongs to
+    pub fn moderator(&self, key: &str) -> Result<String> {
+        // Compare against every key so the time taken doesn't reveal which one nearly matched
+        let mut moderator = None;
+        for (name, moderator_key) in &self.moderator_keys {
+            if constant_time_eq(moderator_key.as_bytes(), key.as_bytes()) {
+                moderator = Some(name.clone());
+            }
+        }
+        moderator.ok_or_else(|| MessageError::Forbidden("not a moderator key".to_string()))
+    }
+}
+
+fn parse_filters(contents: &str) -> std::result::Result<Vec<Regex>, regex::Error> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.strip_prefix("re:") {
+            Some(pattern) => Regex::new(pattern),
+            None => RegexBuilder::new(&format!(r"\b{}\b", regex::escape(line)))
+                .case_insensitive(true)
+                .build(),
+        })
+        .collect()
+}
+
+fn parse_rate_limit(value: &str) -> Option<RateLimit> {
+    let (count, seconds) = value.split_once('/')?;
+    let rate_limit = RateLimit {
+        count: count.trim().parse().ok()?,
+        seconds: seconds.trim().parse().ok()?,
+    };
+    if rate_limit.count == 0 || rate_limit.seconds == 0 {
+        return None;
+    }
+    Some(rate_limit)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+// Users can report messages they could have been shown: ones addressed to
+// them, or someone else's open messages
+pub async fn report_message(store: &dyn MessageStore, user_id: &str, message_id: &str, reason: &str) -> Result<()> {
+    require_active_user(store, user_id).await?;
+    if reason.chars().count() > MAX_REASON_LENGTH {
+        return Err(MessageError::BadRequest(format!(
+            "reason is longer than {} characters",
+            MAX_REASON_LENGTH
+        )));
+    }
+    let reportable = match store.message(message_id).await? {
+        Some(message) => match &message.recipient_id {
+            Some(recipient_id) => recipient_id == user_id,
+            None => message.user_id != user_id,
+        },
+        None => false,
+    };
+    if !reportable {
+        return Err(MessageError::NotFound(format!("no message with id {} for {}", message_id, user_id)));
+    }
+    store
+        .put_report(Report {
+            report_id: Uuid::new_v4().to_string(),
+            message_id: message_id.to_string(),
+            reporter_id: user_id.to_string(),
+            reason: reason.to_string(),
+            created_at: now_millis(),
+        })
+        .await
+}
+
+// The oldest open reports with the message and its author, as a JSON list
+pub async fn moderation_queue(store: &dyn MessageStore, limit: usize) -> Result<String> {
+    if limit == 0 || limit > MAX_QUEUE_PAGE {
+        return Err(MessageError::BadRequest(format!("limit should be between 1 and {}", MAX_QUEUE_PAGE)));
+    }
+    let mut queue = vec![];
+    for report in store.open_reports(limit).await? {
+        let message = store.message(&report.message_id).await?;
+        let author = match &message {
+            Some(message) => store.user(&message.user_id).await?,
+            None => None,
+        };
+        queue.push(json!({
+            "report_id": report.report_id,
+            "reporter_id": report.reporter_id,
+            "reason": report.reason,
+            "created_at": report.created_at,
+            "message": message.map(|message| json!({
+                "message_id": message.message_id,
+                "user_id": message.user_id,
+                "recipient_id": message.recipient_id,
+                "message": message.message,
+                "hidden": message.hidden,
+            })),
+            "author": author.map(|author| json!({
+                "user_id": author.user_id,
+                "user_name": author.user_name,
+                "suspended": author.suspended,
+            })),
+        }));
+    }
+    Ok(Value::Array(queue).to_string())
+}
+
+// Hiding a message settles every report on it
+pub async fn hide_message(store: &dyn MessageStore, message_id: &str) -> Result<()> {
+    if store.message(message_id).await?.is_none() {
+        return Err(MessageError::NotFound(format!("no message with id {}", message_id)));
+    }
+    store.hide_message(message_id).await?;
+    store.close_reports(message_id).await
+}
+
+pub async fn suspend_user(store: &dyn MessageStore, user_id: &str, suspended: bool) -> Result<()> {
+    if store.user(user_id).await?.is_none() {
+        return Err(MessageError::NotFound(format!("no user with id {}", user_id)));
+    }
+    store.set_suspended(user_id, suspended).await
+}
+
+pub async fn dismiss_report(store: &dyn MessageStore, report_id: &str) -> Result<()> {
+    if !store.close_report(report_id).await? {
+        return Err(MessageError::NotFound(format!("no open report with id {}", report_id)));
+    }
+    Ok(())
+}
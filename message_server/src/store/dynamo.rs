@@ -0,0 +1,672 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use rusoto_core::{Region, RusotoError};
+use rusoto_dynamodb::{
+    AttributeValue, BatchGetItemInput, DynamoDb, DynamoDbClient, GetItemInput, KeysAndAttributes, Put, PutItemInput,
+    QueryInput, ScanInput, TransactWriteItem, TransactWriteItemsError, TransactWriteItemsInput, Update,
+    UpdateItemError, UpdateItemInput,
+};
+
+use super::{Message, MessageStore, Report, User};
+use crate::error::{MessageError, Result};
+
+// BatchGetItem takes at most this many keys, and may hand some back unread
+const BATCH_GET_KEYS: usize = 100;
+const BATCH_GET_ATTEMPTS: usize = 3;
+
+// Tables as they were laid out for the Lambda: `users` keyed by user_id with a
+// `user_name_index`, `usernames` keyed by user_name to claim each name once,
+// and `messages` keyed by (user_id, message_id) where the
+// sparse `GSI1` index only holds open messages still in circulation, leaving
+// out those read by someone before receipts existed. `message_id_index` finds
+// a message by id alone, and `conversation_index` is keyed by
+// (conversation_id, created_at) for addressed messages. `read_receipts` is
+// keyed by (reader_id, message_id). `reports` is keyed by report_id, with a
+// sparse `open_reports_index` on (open, created_at) and a
+// `message_reports_index` on message_id. Each user
+// keeps string sets of `friends`, who they `blocked` and who they're
+// `blocked_by`. The old one way `relationships` lists are no longer read.
+pub struct DynamoStore {
+    client: DynamoDbClient,
+}
+
+impl DynamoStore {
+    pub fn new() -> Self {
+        DynamoStore {
+            client: DynamoDbClient::new(Region::UsWest2),
+        }
+    }
+
+    async fn user_item(&self, user_id: &str) -> Result<Option<HashMap<String, AttributeValue>>> {
+        let get_item_input = GetItemInput {
+            key: attributes(&[("user_id", string_value(user_id))]),
+            table_name: String::from("users"),
+            ..Default::default()
+        };
+        let output = self.client.get_item(get_item_input).await.map_err(MessageError::backend)?;
+        Ok(output.item)
+    }
+
+    // Applies every update or none of them, a failed condition is a conflict
+    async fn transact(&self, updates: Vec<Update>) -> Result<()> {
+        self.transact_items(
+            updates
+                .into_iter()
+                .map(|update| TransactWriteItem {
+                    update: Some(update),
+                    ..Default::default()
+                })
+                .collect(),
+        )
+        .await
+    }
+
+    async fn transact_items(&self, transact_items: Vec<TransactWriteItem>) -> Result<()> {
+        let transact_input = TransactWriteItemsInput {
+            transact_items,
+            ..Default::default()
+        };
+        match self.client.transact_write_items(transact_input).await {
+            Ok(_) => Ok(()),
+            Err(RusotoError::Service(TransactWriteItemsError::TransactionCanceled(message))) => {
+                Err(MessageError::Conflict(message))
+            }
+            Err(error) => Err(MessageError::backend(error)),
+        }
+    }
+
+    async fn query_items(&self, mut query_input: QueryInput) -> Result<Vec<HashMap<String, AttributeValue>>> {
+        let mut items = vec![];
+        loop {
+            let output = self.client.query(query_input.clone()).await.map_err(MessageError::backend)?;
+            items.extend(output.items.unwrap_or_default());
+            query_input.exclusive_start_key = match output.last_evaluated_key {
+                Some(key) => Some(key),
+                None => return Ok(items),
+            };
+        }
+    }
+
+    // Which of these messages the reader has a receipt for
+    async fn read_ids(&self, reader_id: &str, message_ids: &[String]) -> Result<HashSet<String>> {
+        let mut read = HashSet::new();
+        for chunk in message_ids.chunks(BATCH_GET_KEYS) {
+            let mut keys: Vec<_> = chunk
+                .iter()
+                .map(|message_id| {
+                    attributes(&[
+                        ("reader_id", string_value(reader_id)),
+                        ("message_id", string_value(message_id)),
+                    ])
+                })
+                .collect();
+            for _ in 0..BATCH_GET_ATTEMPTS {
+                let batch_input = BatchGetItemInput {
+                    request_items: vec![(
+                        String::from("read_receipts"),
+                        KeysAndAttributes {
+                            keys,
+                            ..Default::default()
+                        },
+                    )]
+                    .into_iter()
+                    .collect(),
+                    ..Default::default()
+                };
+                let output = self.client.batch_get_item(batch_input).await.map_err(MessageError::backend)?;
+                let receipts = output.responses.and_then(|mut tables| tables.remove("read_receipts"));
+                read.extend(receipts.unwrap_or_default().iter().filter_map(|item| string_field(item, "message_id")));
+                keys = output
+                    .unprocessed_keys
+                    .and_then(|mut tables| tables.remove("read_receipts"))
+                    .map(|unprocessed| unprocessed.keys)
+                    .unwrap_or_default();
+                if keys.is_empty() {
+                    break;
+                }
+            }
+            if !keys.is_empty() {
+                return Err(MessageError::Backend("read_receipts kept throttling batch reads".to_string()));
+            }
+        }
+        Ok(read)
+    }
+
+    async fn open_messages(&self, author_id: &str) -> Result<Vec<Message>> {
+        let query_input = QueryInput {
+            expression_attribute_values: Some(attributes(&[(":user_id", string_value(author_id))])),
+            key_condition_expression: Some("user_id = :user_id".to_string()),
+            index_name: Some(String::from("GSI1")),
+            table_name: String::from("messages"),
+            ..Default::default()
+        };
+        Ok(visible_messages(self.query_items(query_input).await?))
+    }
+
+    async fn without_read(&self, reader_id: &str, mut messages: Vec<Message>) -> Result<Vec<Message>> {
+        let message_ids: Vec<String> = messages.iter().map(|message| message.message_id.clone()).collect();
+        let read = self.read_ids(reader_id, &message_ids).await?;
+        messages.retain(|message| !read.contains(&message.message_id));
+        Ok(messages)
+    }
+}
+
+fn user_update(user_id: &str, expression: &str, values: &[(&str, AttributeValue)]) -> Update {
+    Update {
+        key: attributes(&[("user_id", string_value(user_id))]),
+        update_expression: expression.to_string(),
+        expression_attribute_values: Some(attributes(values)),
+        table_name: String::from("users"),
+        ..Default::default()
+    }
+}
+
+impl Default for DynamoStore {
+    fn default() -> Self {
+        DynamoStore::new()
+    }
+}
+
+fn string_value(value: &str) -> AttributeValue {
+    AttributeValue {
+        s: Some(value.to_string()),
+        ..Default::default()
+    }
+}
+
+fn attributes(pairs: &[(&str, AttributeValue)]) -> HashMap<String, AttributeValue> {
+    pairs
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect()
+}
+
+fn string_set(value: &str) -> AttributeValue {
+    AttributeValue {
+        ss: Some(vec![value.to_string()]),
+        ..Default::default()
+    }
+}
+
+fn string_field(item: &HashMap<String, AttributeValue>, name: &str) -> Option<String> {
+    item.get(name)?.s.clone()
+}
+
+fn number_value(value: u64) -> AttributeValue {
+    AttributeValue {
+        n: Some(value.to_string()),
+        ..Default::default()
+    }
+}
+
+fn bool_value(value: bool) -> AttributeValue {
+    AttributeValue {
+        bool: Some(value),
+        ..Default::default()
+    }
+}
+
+fn number_field(item: &HashMap<String, AttributeValue>, name: &str) -> Option<u64> {
+    item.get(name)?.n.as_ref()?.parse().ok()
+}
+
+fn bool_field(item: &HashMap<String, AttributeValue>, name: &str) -> bool {
+    item.get(name).and_then(|value| value.bool).unwrap_or(false)
+}
+
+fn string_set_field(item: &HashMap<String, AttributeValue>, name: &str) -> Vec<String> {
+    item.get(name).and_then(|value| value.ss.clone()).unwrap_or_default()
+}
+
+// Messages from before threads are their own thread, written at time zero
+fn to_message(item: &HashMap<String, AttributeValue>) -> Option<Message> {
+    let message_id = string_field(item, "message_id")?;
+    Some(Message {
+        user_id: string_field(item, "user_id")?,
+        message: string_field(item, "message")?,
+        recipient_id: string_field(item, "recipient_id"),
+        reply_to: string_field(item, "reply_to"),
+        thread_id: string_field(item, "thread_id").unwrap_or_else(|| message_id.clone()),
+        created_at: number_field(item, "created_at").unwrap_or(0),
+        hidden: bool_field(item, "hidden"),
+        message_id,
+    })
+}
+
+fn visible_messages(items: Vec<HashMap<String, AttributeValue>>) -> Vec<Message> {
+    items.iter().filter_map(to_message).filter(|message| !message.hidden).collect()
+}
+
+fn to_user(item: &HashMap<String, AttributeValue>) -> Option<User> {
+    Some(User {
+        user_id: string_field(item, "user_id")?,
+        user_name: string_field(item, "user_name")?,
+        suspended: bool_field(item, "suspended"),
+        password_hash: string_field(item, "password_hash"),
+    })
+}
+
+fn to_report(item: &HashMap<String, AttributeValue>) -> Option<Report> {
+    Some(Report {
+        report_id: string_field(item, "report_id")?,
+        message_id: string_field(item, "message_id")?,
+        reporter_id: string_field(item, "reporter_id")?,
+        reason: string_field(item, "reason")?,
+        created_at: number_field(item, "created_at")?,
+    })
+}
+
+// The same for both directions, so one query finds the whole conversation
+fn conversation_id(user_id: &str, friend_id: &str) -> String {
+    if user_id < friend_id {
+        format!("{}:{}", user_id, friend_id)
+    } else {
+        format!("{}:{}", friend_id, user_id)
+    }
+}
+
+#[async_trait]
+impl MessageStore for DynamoStore {
+    async fn user(&self, user_id: &str) -> Result<Option<User>> {
+        Ok(self.user_item(user_id).await?.as_ref().and_then(to_user))
+    }
+
+    async fn find_user(&self, user_name: &str) -> Result<Option<User>> {
+        let get_item_input = GetItemInput {
+            key: attributes(&[("user_name", string_value(user_name))]),
+            consistent_read: Some(true),
+            table_name: String::from("usernames"),
+            ..Default::default()
+        };
+        let output = self.client.get_item(get_item_input).await.map_err(MessageError::backend)?;
+        if let Some(user_id) = output.item.as_ref().and_then(|item| string_field(item, "user_id")) {
+            return self.user(&user_id).await;
+        }
+        // Users made before `usernames` existed are only in the index
+        let query_input = QueryInput {
+            expression_attribute_values: Some(attributes(&[(":user_name", string_value(user_name))])),
+            key_condition_expression: Some("user_name = :user_name".to_string()),
+            index_name: Some(String::from("user_name_index")),
+            table_name: String::from("users"),
+            ..Default::default()
+        };
+        let output = self.client.query(query_input).await.map_err(MessageError::backend)?;
+        let items = output.items.unwrap_or_default();
+        if items.len() > 1 {
+            return Err(MessageError::Backend(format!("more than one user named {}", user_name)));
+        }
+        // The index may not project every attribute, so read the whole user
+        match items.first().and_then(|item| string_field(item, "user_id")) {
+            Some(user_id) => self.user(&user_id).await,
+            None => Ok(None),
+        }
+    }
+
+    // The name is claimed in `usernames` in the same transaction as the user
+    // is written, so two users can't end up sharing it
+    async fn create_user(&self, user_name: &str, password_hash: &str) -> Result<User> {
+        let taken = || MessageError::Conflict(format!("the username {} is taken", user_name));
+        if self.find_user(user_name).await?.is_some() {
+            return Err(taken());
+        }
+        let user_id = Uuid::new_v4().to_string();
+        let claim = Put {
+            item: attributes(&[
+                ("user_name", string_value(user_name)),
+                ("user_id", string_value(&user_id)),
+            ]),
+            condition_expression: Some("attribute_not_exists(user_name)".to_string()),
+            table_name: String::from("usernames"),
+            ..Default::default()
+        };
+        let user = Put {
+            item: attributes(&[
+                ("user_id", string_value(&user_id)),
+                ("user_name", string_value(user_name)),
+                ("password_hash", string_value(password_hash)),
+            ]),
+            condition_expression: Some("attribute_not_exists(user_id)".to_string()),
+            table_name: String::from("users"),
+            ..Default::default()
+        };
+        let items = vec![claim, user]
+            .into_iter()
+            .map(|put| TransactWriteItem {
+                put: Some(put),
+                ..Default::default()
+            })
+            .collect();
+        match self.transact_items(items).await {
+            Ok(()) => {
+                println!("User created.");
+                Ok(User {
+                    user_id,
+                    user_name: user_name.to_string(),
+                    suspended: false,
+                    password_hash: Some(password_hash.to_string()),
+                })
+            }
+            Err(MessageError::Conflict(_)) => Err(taken()),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn set_suspended(&self, user_id: &str, suspended: bool) -> Result<()> {
+        let update_input = UpdateItemInput {
+            key: attributes(&[("user_id", string_value(user_id))]),
+            update_expression: Some("SET suspended = :suspended".to_string()),
+            expression_attribute_values: Some(attributes(&[(":suspended", bool_value(suspended))])),
+            table_name: String::from("users"),
+            ..Default::default()
+        };
+        self.client.update_item(update_input).await.map_err(MessageError::backend)?;
+        Ok(())
+    }
+
+    async fn relationships(&self, user_id: &str) -> Result<Vec<String>> {
+        Ok(self
+            .user_item(user_id)
+            .await?
+            .map(|item| string_set_field(&item, "friends"))
+            .unwrap_or_default())
+    }
+
+    async fn add_relationship(&self, user_id: &str, friend_id: &str) -> Result<()> {
+        // Each side only takes the link if it exists and hasn't blocked, or
+        // been blocked by, the other
+        let link = |from: &str, to: &str| Update {
+            condition_expression: Some(
+                "attribute_exists(user_id) AND NOT contains(blocked, :other) AND NOT contains(blocked_by, :other)"
+                    .to_string(),
+            ),
+            ..user_update(from, "ADD friends :others", &[(":other", string_value(to)), (":others", string_set(to))])
+        };
+        self.transact(vec![link(user_id, friend_id), link(friend_id, user_id)])
+            .await
+            .map_err(|error| match error {
+                MessageError::Conflict(_) => {
+                    MessageError::Conflict(format!("{} and {} can't be friends", user_id, friend_id))
+                }
+                error => error,
+            })?;
+        println!("Relationship created");
+        Ok(())
+    }
+
+    async fn remove_relationship(&self, user_id: &str, friend_id: &str) -> Result<bool> {
+        if !self.relationships(user_id).await?.iter().any(|friend| friend == friend_id) {
+            return Ok(false);
+        }
+        let unlink = |from: &str, to: &str| user_update(from, "DELETE friends :others", &[(":others", string_set(to))]);
+        self.transact(vec![unlink(user_id, friend_id), unlink(friend_id, user_id)]).await?;
+        Ok(true)
+    }
+
+    async fn block_user(&self, user_id: &str, blocked_id: &str) -> Result<()> {
+        // Updates create missing items, so both users have to exist already
+        let block = |from: &str, expression: &str, to: &str| Update {
+            condition_expression: Some("attribute_exists(user_id)".to_string()),
+            ..user_update(from, expression, &[(":others", string_set(to))])
+        };
+        self.transact(vec![
+            block(user_id, "ADD blocked :others DELETE friends :others", blocked_id),
+            block(blocked_id, "ADD blocked_by :others DELETE friends :others", user_id),
+        ])
+        .await
+        .map_err(|error| match error {
+            MessageError::Conflict(_) => {
+                MessageError::NotFound(format!("{} or {} no longer exists", user_id, blocked_id))
+            }
+            error => error,
+        })
+    }
+
+    // GSI1 only holds open messages, so scanning it walks the authors with
+    // something to read. Open messages stay in the index once read, so each
+    // page is checked against the reader's receipts and the scan goes on
+    // until there are enough authors with something unread.
+    async fn match_candidates(&self, user_id: &str, limit: usize) -> Result<Vec<String>> {
+        let mut excluded: HashSet<String> = match self.user_item(user_id).await? {
+            Some(item) => ["friends", "blocked", "blocked_by"]
+                .iter()
+                .flat_map(|name| string_set_field(&item, name))
+                .collect(),
+            None => HashSet::new(),
+        };
+        excluded.insert(user_id.to_string());
+        let mut candidates: Vec<String> = vec![];
+        let mut start_key = None;
+        while candidates.len() < limit {
+            let scan_input = ScanInput {
+                exclusive_start_key: start_key,
+                index_name: Some(String::from("GSI1")),
+                projection_expression: Some("user_id, message_id".to_string()),
+                table_name: String::from("messages"),
+                ..Default::default()
+            };
+            let output = self.client.scan(scan_input).await.map_err(MessageError::backend)?;
+            let open: Vec<(String, String)> = output
+                .items
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|item| Some((string_field(item, "user_id")?, string_field(item, "message_id")?)))
+                .filter(|(author, _)| !excluded.contains(author) && !candidates.contains(author))
+                .collect();
+            let message_ids: Vec<String> = open.iter().map(|(_, message_id)| message_id.clone()).collect();
+            let read = self.read_ids(user_id, &message_ids).await?;
+            for (author, message_id) in open {
+                if read.contains(&message_id) || excluded.contains(&author) || candidates.contains(&author) {
+                    continue;
+                }
+                if self.user(&author).await?.is_some_and(|author| !author.suspended) {
+                    candidates.push(author);
+                } else {
+                    excluded.insert(author);
+                }
+            }
+            start_key = match output.last_evaluated_key {
+                Some(key) => Some(key),
+                None => break,
+            };
+        }
+        candidates.truncate(limit);
+        Ok(candidates)
+    }
+
+    async fn put_message(&self, message: Message) -> Result<()> {
+        let mut item = attributes(&[
+            ("user_id", string_value(&message.user_id)),
+            ("message_id", string_value(&message.message_id)),
+            ("message", string_value(&message.message)),
+            ("thread_id", string_value(&message.thread_id)),
+            ("created_at", number_value(message.created_at)),
+            ("hidden", bool_value(message.hidden)),
+        ]);
+        if let Some(reply_to) = &message.reply_to {
+            item.insert(String::from("reply_to"), string_value(reply_to));
+        }
+        // Addressed messages stay out of GSI1 so matchmaking never sees them
+        match &message.recipient_id {
+            Some(recipient_id) => {
+                item.insert(String::from("recipient_id"), string_value(recipient_id));
+                item.insert(
+                    String::from("conversation_id"),
+                    string_value(&conversation_id(&message.user_id, recipient_id)),
+                );
+            }
+            None => {
+                item.insert(String::from("unread"), string_value("t"));
+            }
+        }
+        let put_input = PutItemInput {
+            item,
+            table_name: String::from("messages"),
+            ..Default::default()
+        };
+        self.client.put_item(put_input).await.map_err(MessageError::backend)?;
+        println!("Message sent!");
+        Ok(())
+    }
+
+    async fn message(&self, message_id: &str) -> Result<Option<Message>> {
+        let query_input = QueryInput {
+            expression_attribute_values: Some(attributes(&[(":message_id", string_value(message_id))])),
+            key_condition_expression: Some("message_id = :message_id".to_string()),
+            index_name: Some(String::from("message_id_index")),
+            table_name: String::from("messages"),
+            ..Default::default()
+        };
+        let output = self.client.query(query_input).await.map_err(MessageError::backend)?;
+        Ok(output.items.unwrap_or_default().iter().find_map(to_message))
+    }
+
+    async fn recent_message_count(&self, author_id: &str, since: u64) -> Result<usize> {
+        let mut query_input = QueryInput {
+            expression_attribute_values: Some(attributes(&[
+                (":user_id", string_value(author_id)),
+                (":since", number_value(since)),
+            ])),
+            key_condition_expression: Some("user_id = :user_id".to_string()),
+            filter_expression: Some("created_at >= :since".to_string()),
+            select: Some("COUNT".to_string()),
+            table_name: String::from("messages"),
+            ..Default::default()
+        };
+        let mut count = 0;
+        loop {
+            let output = self.client.query(query_input.clone()).await.map_err(MessageError::backend)?;
+            count += output.count.unwrap_or(0) as usize;
+            query_input.exclusive_start_key = match output.last_evaluated_key {
+                Some(key) => Some(key),
+                None => return Ok(count),
+            };
+        }
+    }
+
+    // Dropping `unread` also takes it out of GSI1
+    async fn hide_message(&self, message_id: &str) -> Result<()> {
+        let message = match self.message(message_id).await? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        let update_input = UpdateItemInput {
+            key: attributes(&[
+                ("user_id", string_value(&message.user_id)),
+                ("message_id", string_value(message_id)),
+            ]),
+            update_expression: Some("SET hidden = :hidden REMOVE unread".to_string()),
+            expression_attribute_values: Some(attributes(&[(":hidden", bool_value(true))])),
+            table_name: String::from("messages"),
+            ..Default::default()
+        };
+        self.client.update_item(update_input).await.map_err(MessageError::backend)?;
+        Ok(())
+    }
+
+    async fn unread_messages(&self, author_id: &str, reader_id: &str) -> Result<Vec<Message>> {
+        let open = self.open_messages(author_id).await?;
+        let mut unread = self.without_read(reader_id, open).await?;
+        unread.sort_by(|a, b| a.delivery_order().cmp(&b.delivery_order()));
+        Ok(unread)
+    }
+
+    async fn unread_count(&self, author_id: &str, reader_id: &str) -> Result<usize> {
+        let mut messages = self.open_messages(author_id).await?;
+        let addressed = self.conversation(author_id, reader_id).await?;
+        messages.extend(addressed.into_iter().filter(|message| message.user_id == author_id));
+        Ok(self.without_read(reader_id, messages).await?.len())
+    }
+
+    async fn conversation(&self, user_id: &str, friend_id: &str) -> Result<Vec<Message>> {
+        let query_input = QueryInput {
+            expression_attribute_values: Some(attributes(&[(
+                ":conversation_id",
+                string_value(&conversation_id(user_id, friend_id)),
+            )])),
+            key_condition_expression: Some("conversation_id = :conversation_id".to_string()),
+            index_name: Some(String::from("conversation_index")),
+            table_name: String::from("messages"),
+            ..Default::default()
+        };
+        Ok(visible_messages(self.query_items(query_input).await?))
+    }
+
+    async fn mark_read(&self, reader_id: &str, message_id: &str) -> Result<()> {
+        let read_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let put_input = PutItemInput {
+            item: attributes(&[
+                ("reader_id", string_value(reader_id)),
+                ("message_id", string_value(message_id)),
+                ("read_at", number_value(read_at)),
+            ]),
+            table_name: String::from("read_receipts"),
+            ..Default::default()
+        };
+        self.client.put_item(put_input).await.map_err(MessageError::backend)?;
+        Ok(())
+    }
+
+    async fn put_report(&self, report: Report) -> Result<()> {
+        let put_input = PutItemInput {
+            item: attributes(&[
+                ("report_id", string_value(&report.report_id)),
+                ("message_id", string_value(&report.message_id)),
+                ("reporter_id", string_value(&report.reporter_id)),
+                ("reason", string_value(&report.reason)),
+                ("created_at", number_value(report.created_at)),
+                ("open", string_value("t")),
+            ]),
+            table_name: String::from("reports"),
+            ..Default::default()
+        };
+        self.client.put_item(put_input).await.map_err(MessageError::backend)?;
+        Ok(())
+    }
+
+    async fn open_reports(&self, limit: usize) -> Result<Vec<Report>> {
+        let query_input = QueryInput {
+            expression_attribute_values: Some(attributes(&[(":open", string_value("t"))])),
+            key_condition_expression: Some("#open = :open".to_string()),
+            expression_attribute_names: Some(vec![("#open".to_string(), "open".to_string())].into_iter().collect()),
+            index_name: Some(String::from("open_reports_index")),
+            limit: Some(limit as i64),
+            table_name: String::from("reports"),
+            ..Default::default()
+        };
+        let output = self.client.query(query_input).await.map_err(MessageError::backend)?;
+        Ok(output.items.unwrap_or_default().iter().filter_map(to_report).collect())
+    }
+
+    async fn close_report(&self, report_id: &str) -> Result<bool> {
+        let update_input = UpdateItemInput {
+            key: attributes(&[("report_id", string_value(report_id))]),
+            update_expression: Some("REMOVE #open".to_string()),
+            condition_expression: Some("attribute_exists(#open)".to_string()),
+            expression_attribute_names: Some(vec![("#open".to_string(), "open".to_string())].into_iter().collect()),
+            table_name: String::from("reports"),
+            ..Default::default()
+        };
+        match self.client.update_item(update_input).await {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(error) => Err(MessageError::backend(error)),
+        }
+    }
+
+    async fn close_reports(&self, message_id: &str) -> Result<()> {
+        let query_input = QueryInput {
+            expression_attribute_values: Some(attributes(&[(":message_id", string_value(message_id))])),
+            key_condition_expression: Some("message_id = :message_id".to_string()),
+            index_name: Some(String::from("message_reports_index")),
+            table_name: String::from("reports"),
+            ..Default::default()
+        };
+        for report in self.query_items(query_input).await?.iter().filter_map(to_report) {
+            self.close_report(&report.report_id).await?;
+        }
+        Ok(())
+    }
+}
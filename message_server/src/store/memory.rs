@@ -0,0 +1,252 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use super::{Message, MessageStore, Report, User};
+use crate::error::{MessageError, Result};
+
+// Keeps everything in process, for running locally and in tests.
+// Nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    users: HashMap<String, User>,
+    relationships: HashMap<String, Vec<String>>,
+    // (blocker, blocked) pairs
+    blocks: HashSet<(String, String)>,
+    messages: Vec<Message>,
+    // (reader, message_id) pairs
+    receipts: HashSet<(String, String)>,
+    reports: Vec<Report>,
+    open_reports: HashSet<String>,
+}
+
+impl MemoryState {
+    fn blocked_either_way(&self, user_id: &str, other_id: &str) -> bool {
+        self.blocks.contains(&(user_id.to_string(), other_id.to_string()))
+            || self.blocks.contains(&(other_id.to_string(), user_id.to_string()))
+    }
+
+    fn visible_messages(&self) -> impl Iterator<Item = &Message> {
+        self.messages.iter().filter(|message| !message.hidden)
+    }
+
+    fn unread_by(&self, reader_id: &str) -> impl Fn(&&Message) -> bool + '_ {
+        let reader_id = reader_id.to_string();
+        move |message| !self.receipts.contains(&(reader_id.clone(), message.message_id.clone()))
+    }
+
+    fn unlink(&mut self, user_id: &str, other_id: &str) -> bool {
+        let mut removed = false;
+        for (from, to) in [(user_id, other_id), (other_id, user_id)] {
+            if let Some(relationships) = self.relationships.get_mut(from) {
+                let before = relationships.len();
+                relationships.retain(|friend| friend != to);
+                removed |= relationships.len() != before;
+            }
+        }
+        removed
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryStore {
+    async fn user(&self, user_id: &str) -> Result<Option<User>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.users.get(user_id).cloned())
+    }
+
+    async fn find_user(&self, user_name: &str) -> Result<Option<User>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.users.values().find(|user| user.user_name == user_name).cloned())
+    }
+
+    async fn create_user(&self, user_name: &str, password_hash: &str) -> Result<User> {
+        let mut state = self.state.lock().unwrap();
+        if state.users.values().any(|user| user.user_name == user_name) {
+            return Err(MessageError::Conflict(format!("the username {} is taken", user_name)));
+        }
+        let user = User {
+            user_id: Uuid::new_v4().to_string(),
+            user_name: user_name.to_string(),
+            suspended: false,
+            password_hash: Some(password_hash.to_string()),
+        };
+        state.users.insert(user.user_id.clone(), user.clone());
+        state.relationships.insert(user.user_id.clone(), vec![]);
+        Ok(user)
+    }
+
+    async fn set_suspended(&self, user_id: &str, suspended: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(user) = state.users.get_mut(user_id) {
+            user.suspended = suspended;
+        }
+        Ok(())
+    }
+
+    async fn relationships(&self, user_id: &str) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.relationships.get(user_id).cloned().unwrap_or_default())
+    }
+
+    async fn add_relationship(&self, user_id: &str, friend_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.blocked_either_way(user_id, friend_id) {
+            return Err(MessageError::Conflict(format!("{} and {} can't be friends", user_id, friend_id)));
+        }
+        for (from, to) in [(user_id, friend_id), (friend_id, user_id)] {
+            let relationships = state.relationships.entry(from.to_string()).or_default();
+            if !relationships.iter().any(|friend| friend == to) {
+                relationships.push(to.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_relationship(&self, user_id: &str, friend_id: &str) -> Result<bool> {
+        Ok(self.state.lock().unwrap().unlink(user_id, friend_id))
+    }
+
+    async fn block_user(&self, user_id: &str, blocked_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.unlink(user_id, blocked_id);
+        state.blocks.insert((user_id.to_string(), blocked_id.to_string()));
+        Ok(())
+    }
+
+    async fn match_candidates(&self, user_id: &str, limit: usize) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        let relationships = state.relationships.get(user_id);
+        // Messages are in the order they were written, so authors come out
+        // by their oldest unread message
+        let mut candidates: Vec<String> = vec![];
+        let authors = state
+            .visible_messages()
+            .filter(|message| message.recipient_id.is_none() && message.user_id != user_id)
+            .filter(state.unread_by(user_id))
+            .map(|message| &message.user_id)
+            .filter(|author| !relationships.is_some_and(|friends| friends.contains(author)))
+            .filter(|author| !state.users.get(*author).is_some_and(|user| user.suspended))
+            .filter(|author| !state.blocked_either_way(user_id, author));
+        for author in authors {
+            if candidates.len() == limit {
+                break;
+            }
+            if !candidates.contains(author) {
+                candidates.push(author.clone());
+            }
+        }
+        Ok(candidates)
+    }
+
+    async fn put_message(&self, message: Message) -> Result<()> {
+        self.state.lock().unwrap().messages.push(message);
+        Ok(())
+    }
+
+    async fn message(&self, message_id: &str) -> Result<Option<Message>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.messages.iter().find(|message| message.message_id == message_id).cloned())
+    }
+
+    async fn recent_message_count(&self, author_id: &str, since: u64) -> Result<usize> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .messages
+            .iter()
+            .filter(|message| message.user_id == author_id && message.created_at >= since)
+            .count())
+    }
+
+    async fn hide_message(&self, message_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(message) = state.messages.iter_mut().find(|message| message.message_id == message_id) {
+            message.hidden = true;
+        }
+        Ok(())
+    }
+
+    async fn unread_messages(&self, author_id: &str, reader_id: &str) -> Result<Vec<Message>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .visible_messages()
+            .filter(|message| message.user_id == author_id && message.recipient_id.is_none())
+            .filter(state.unread_by(reader_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn unread_count(&self, author_id: &str, reader_id: &str) -> Result<usize> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .visible_messages()
+            .filter(|message| {
+                message.user_id == author_id
+                    && message.recipient_id.as_ref().map_or(true, |recipient_id| recipient_id == reader_id)
+            })
+            .filter(state.unread_by(reader_id))
+            .count())
+    }
+
+    async fn conversation(&self, user_id: &str, friend_id: &str) -> Result<Vec<Message>> {
+        let state = self.state.lock().unwrap();
+        let between = |message: &&Message| match &message.recipient_id {
+            Some(recipient_id) => {
+                (message.user_id == user_id && recipient_id == friend_id)
+                    || (message.user_id == friend_id && recipient_id == user_id)
+            }
+            None => false,
+        };
+        // Messages are kept in the order they were written
+        Ok(state.visible_messages().filter(between).cloned().collect())
+    }
+
+    async fn mark_read(&self, reader_id: &str, message_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.receipts.insert((reader_id.to_string(), message_id.to_string()));
+        Ok(())
+    }
+
+    async fn put_report(&self, report: Report) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.open_reports.insert(report.report_id.clone());
+        state.reports.push(report);
+        Ok(())
+    }
+
+    async fn open_reports(&self, limit: usize) -> Result<Vec<Report>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .reports
+            .iter()
+            .filter(|report| state.open_reports.contains(&report.report_id))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn close_report(&self, report_id: &str) -> Result<bool> {
+        Ok(self.state.lock().unwrap().open_reports.remove(report_id))
+    }
+
+    async fn close_reports(&self, message_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let MemoryState { reports, open_reports, .. } = &mut *state;
+        for report in reports.iter().filter(|report| report.message_id == message_id) {
+            open_reports.remove(&report.report_id);
+        }
+        Ok(())
+    }
+}
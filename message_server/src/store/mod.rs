@@ -0,0 +1,129 @@
+use async_trait::async_trait;
+use std::env;
+
+use crate::error::Result;
+
+mod dynamo;
+mod memory;
+mod sqlite;
+
+pub use dynamo::DynamoStore;
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+// Picks the backend: `dynamodb` (the default), `memory`, or `sqlite:<path>`
+pub const STORE_VAR: &str = "MESSAGE_STORE";
+
+#[derive(Clone, Debug)]
+pub struct User {
+    pub user_id: String,
+    pub user_name: String,
+    // Set by a moderator, keeps the user from writing or being matched
+    pub suspended: bool,
+    // Argon2 PHC string. Users made before accounts had passwords have none
+    // and can't log in.
+    pub password_hash: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    // The author of the message
+    pub user_id: String,
+    pub message_id: String,
+    pub message: String,
+    // Who the message is for. Open messages have none and go to whoever
+    // gets matched with the author.
+    pub recipient_id: Option<String>,
+    // The message this one answers
+    pub reply_to: Option<String>,
+    // The first message of the reply chain, the message's own id if it
+    // doesn't answer anything
+    pub thread_id: String,
+    // Milliseconds since the Unix epoch
+    pub created_at: u64,
+    // Taken down by a moderator, so it's never delivered
+    pub hidden: bool,
+}
+
+impl Message {
+    // Messages are delivered oldest first, the id breaking ties
+    pub fn delivery_order(&self) -> (u64, &str) {
+        (self.created_at, &self.message_id)
+    }
+}
+
+// A user flagging a message for the moderators. Reports stay open until a
+// moderator acts on the message or dismisses them.
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub report_id: String,
+    pub message_id: String,
+    pub reporter_id: String,
+    pub reason: String,
+    pub created_at: u64,
+}
+
+// Everything the message flow needs from storage. Lookups that find nothing
+// return `None` or an empty list, while backend failures are errors.
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    async fn user(&self, user_id: &str) -> Result<Option<User>>;
+    async fn find_user(&self, user_name: &str) -> Result<Option<User>>;
+    // Fails with a conflict if the name is taken, even by a user being
+    // created at the same moment
+    async fn create_user(&self, user_name: &str, password_hash: &str) -> Result<User>;
+    async fn set_suspended(&self, user_id: &str, suspended: bool) -> Result<()>;
+
+    // Ids of this user's friends. Relationships always go both ways.
+    async fn relationships(&self, user_id: &str) -> Result<Vec<String>>;
+    // Links both users in one write, doing nothing if they already are.
+    // Fails with a conflict if either has blocked the other.
+    async fn add_relationship(&self, user_id: &str, friend_id: &str) -> Result<()>;
+    // Unlinks both users, false if they weren't friends
+    async fn remove_relationship(&self, user_id: &str, friend_id: &str) -> Result<bool>;
+    // Unlinks both users and keeps them from being matched again
+    async fn block_user(&self, user_id: &str, blocked_id: &str) -> Result<()>;
+    // Up to `limit` authors with open messages the user hasn't read, leaving
+    // out their friends, suspended authors and anyone blocked in either
+    // direction
+    async fn match_candidates(&self, user_id: &str, limit: usize) -> Result<Vec<String>>;
+
+    async fn put_message(&self, message: Message) -> Result<()>;
+    // Finds hidden messages too
+    async fn message(&self, message_id: &str) -> Result<Option<Message>>;
+    // How many messages the author wrote at or after `since`
+    async fn recent_message_count(&self, author_id: &str, since: u64) -> Result<usize>;
+    async fn hide_message(&self, message_id: &str) -> Result<()>;
+    // These three leave out hidden messages
+    // Open messages by the author that the reader hasn't read, oldest first
+    async fn unread_messages(&self, author_id: &str, reader_id: &str) -> Result<Vec<Message>>;
+    // How many of the author's open messages, and messages to the reader,
+    // the reader hasn't read
+    async fn unread_count(&self, author_id: &str, reader_id: &str) -> Result<usize>;
+    // Messages addressed between the two users, oldest first
+    async fn conversation(&self, user_id: &str, friend_id: &str) -> Result<Vec<Message>>;
+    // Records a read receipt for this reader alone. Reading twice is fine.
+    async fn mark_read(&self, reader_id: &str, message_id: &str) -> Result<()>;
+
+    async fn put_report(&self, report: Report) -> Result<()>;
+    // Up to `limit` open reports, oldest first
+    async fn open_reports(&self, limit: usize) -> Result<Vec<Report>>;
+    // Closes one report, false if it wasn't open
+    async fn close_report(&self, report_id: &str) -> Result<bool>;
+    // Closes every open report on the message
+    async fn close_reports(&self, message_id: &str) -> Result<()>;
+}
+
+pub fn from_env() -> std::result::Result<Box<dyn MessageStore>, String> {
+    let setting = env::var(STORE_VAR).unwrap_or_else(|_| "dynamodb".to_string());
+    match setting.as_str() {
+        "dynamodb" => Ok(Box::new(DynamoStore::new())),
+        "memory" => Ok(Box::new(MemoryStore::new())),
+        _ => match setting.strip_prefix("sqlite:") {
+            Some(path) => SqliteStore::open(path)
+                .map(|store| Box::new(store) as Box<dyn MessageStore>)
+                .map_err(|e| format!("could not open SQLite store at {}: {}", path, e)),
+            None => Err(format!("{} should be dynamodb, memory or sqlite:<path>, not {}", STORE_VAR, setting)),
+        },
+    }
+}
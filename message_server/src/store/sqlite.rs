@@ -0,0 +1,428 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use super::{Message, MessageStore, Report, User};
+use crate::error::{MessageError, Result};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        user_id TEXT PRIMARY KEY,
+        user_name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE IF NOT EXISTS relationships (
+        user_id TEXT NOT NULL REFERENCES users (user_id),
+        friend_id TEXT NOT NULL REFERENCES users (user_id)
+    );
+    CREATE TABLE IF NOT EXISTS blocks (
+        user_id TEXT NOT NULL REFERENCES users (user_id),
+        blocked_id TEXT NOT NULL REFERENCES users (user_id),
+        PRIMARY KEY (user_id, blocked_id)
+    );
+    -- `unread` predates read receipts. New messages keep it set, while open
+    -- messages someone read before receipts existed stay out of circulation.
+    CREATE TABLE IF NOT EXISTS messages (
+        message_id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users (user_id),
+        message TEXT NOT NULL,
+        unread INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS read_receipts (
+        reader_id TEXT NOT NULL REFERENCES users (user_id),
+        message_id TEXT NOT NULL REFERENCES messages (message_id),
+        read_at INTEGER NOT NULL,
+        PRIMARY KEY (reader_id, message_id)
+    );
+    CREATE TABLE IF NOT EXISTS reports (
+        report_id TEXT PRIMARY KEY,
+        message_id TEXT NOT NULL REFERENCES messages (message_id),
+        reporter_id TEXT NOT NULL REFERENCES users (user_id),
+        reason TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        open INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS open_reports ON reports (created_at) WHERE open;
+
+    -- Relationships used to be one way and could repeat. Mirror and dedupe
+    -- them before the unique index goes on.
+    INSERT INTO relationships (user_id, friend_id)
+        SELECT friend_id, user_id FROM relationships r
+        WHERE NOT EXISTS (
+            SELECT 1 FROM relationships WHERE user_id = r.friend_id AND friend_id = r.user_id
+        );
+    DELETE FROM relationships WHERE rowid NOT IN (
+        SELECT MIN(rowid) FROM relationships GROUP BY user_id, friend_id
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS relationship_pairs ON relationships (user_id, friend_id);
+";
+
+// Columns added after the first schema as (table, column, definition), so
+// older databases get them on open
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("messages", "recipient_id", "TEXT REFERENCES users (user_id)"),
+    ("messages", "reply_to", "TEXT"),
+    ("messages", "thread_id", "TEXT"),
+    ("messages", "created_at", "INTEGER NOT NULL DEFAULT 0"),
+    ("messages", "hidden", "INTEGER NOT NULL DEFAULT 0"),
+    ("users", "suspended", "INTEGER NOT NULL DEFAULT 0"),
+    ("users", "password_hash", "TEXT"),
+];
+
+const ADDED_COLUMN_INDEXES: &str = "
+    UPDATE messages SET thread_id = message_id WHERE thread_id IS NULL;
+    CREATE INDEX IF NOT EXISTS unread_messages ON messages (user_id) WHERE unread;
+    CREATE INDEX IF NOT EXISTS conversations ON messages (user_id, recipient_id, created_at)
+        WHERE recipient_id IS NOT NULL;
+";
+
+const MESSAGE_FIELDS: &str = "user_id, message_id, message, recipient_id, reply_to, thread_id, created_at, hidden";
+const USER_FIELDS: &str = "user_id, user_name, suspended, password_hash";
+const REPORT_FIELDS: &str = "report_id, message_id, reporter_id, reason, created_at";
+
+// Filters `messages` down to the ones reader ?2 has no receipt for
+const UNREAD_BY_READER: &str =
+    "NOT EXISTS (SELECT 1 FROM read_receipts WHERE reader_id = ?2 AND message_id = messages.message_id)";
+
+fn to_message(row: &Row) -> rusqlite::Result<Message> {
+    Ok(Message {
+        user_id: row.get(0)?,
+        message_id: row.get(1)?,
+        message: row.get(2)?,
+        recipient_id: row.get(3)?,
+        reply_to: row.get(4)?,
+        thread_id: row.get(5)?,
+        created_at: row.get::<_, i64>(6)? as u64,
+        hidden: row.get(7)?,
+    })
+}
+
+fn to_user(row: &Row) -> rusqlite::Result<User> {
+    Ok(User {
+        user_id: row.get(0)?,
+        user_name: row.get(1)?,
+        suspended: row.get(2)?,
+        password_hash: row.get(3)?,
+    })
+}
+
+fn to_report(row: &Row) -> rusqlite::Result<Report> {
+    Ok(Report {
+        report_id: row.get(0)?,
+        message_id: row.get(1)?,
+        reporter_id: row.get(2)?,
+        reason: row.get(3)?,
+        created_at: row.get::<_, i64>(4)? as u64,
+    })
+}
+
+// A single file database for running on one box without AWS. Queries are
+// small enough that holding the connection lock across them is fine.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    // `:memory:` gives a throwaway database
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        for (table, name, definition) in ADDED_COLUMNS {
+            let exists: bool = connection.query_row(
+                "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+                params![table, name],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                connection.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, name, definition))?;
+            }
+        }
+        connection.execute_batch(ADDED_COLUMN_INDEXES)?;
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn with_connection<T>(&self, f: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> Result<T> {
+        let connection = self.connection.lock().unwrap();
+        Ok(f(&connection)?)
+    }
+}
+
+#[async_trait]
+impl MessageStore for SqliteStore {
+    async fn user(&self, user_id: &str) -> Result<Option<User>> {
+        self.with_connection(|connection| {
+            connection
+                .query_row(
+                    &format!("SELECT {} FROM users WHERE user_id = ?1", USER_FIELDS),
+                    params![user_id],
+                    to_user,
+                )
+                .optional()
+        })
+    }
+
+    async fn find_user(&self, user_name: &str) -> Result<Option<User>> {
+        self.with_connection(|connection| {
+            connection
+                .query_row(
+                    &format!("SELECT {} FROM users WHERE user_name = ?1", USER_FIELDS),
+                    params![user_name],
+                    to_user,
+                )
+                .optional()
+        })
+    }
+
+    async fn create_user(&self, user_name: &str, password_hash: &str) -> Result<User> {
+        let user = User {
+            user_id: Uuid::new_v4().to_string(),
+            user_name: user_name.to_string(),
+            suspended: false,
+            password_hash: Some(password_hash.to_string()),
+        };
+        let created = self.with_connection(|connection| {
+            connection.execute(
+                "INSERT INTO users (user_id, user_name, password_hash) VALUES (?1, ?2, ?3)",
+                params![user.user_id, user.user_name, user.password_hash],
+            )
+        });
+        match created {
+            Ok(_) => Ok(user),
+            Err(MessageError::Conflict(_)) => Err(MessageError::Conflict(format!("the username {} is taken", user_name))),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set_suspended(&self, user_id: &str, suspended: bool) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute("UPDATE users SET suspended = ?2 WHERE user_id = ?1", params![user_id, suspended])?;
+            Ok(())
+        })
+    }
+
+    async fn relationships(&self, user_id: &str) -> Result<Vec<String>> {
+        self.with_connection(|connection| {
+            let mut statement =
+                connection.prepare("SELECT friend_id FROM relationships WHERE user_id = ?1 ORDER BY rowid")?;
+            let friends = statement.query_map(params![user_id], |row| row.get(0))?;
+            friends.collect()
+        })
+    }
+
+    async fn add_relationship(&self, user_id: &str, friend_id: &str) -> Result<()> {
+        let added = self.with_connection(|connection| {
+            let transaction = connection.unchecked_transaction()?;
+            let blocked: bool = transaction.query_row(
+                "SELECT EXISTS (SELECT 1 FROM blocks
+                 WHERE (user_id = ?1 AND blocked_id = ?2) OR (user_id = ?2 AND blocked_id = ?1))",
+                params![user_id, friend_id],
+                |row| row.get(0),
+            )?;
+            if blocked {
+                return Ok(false);
+            }
+            let mut statement =
+                transaction.prepare("INSERT OR IGNORE INTO relationships (user_id, friend_id) VALUES (?1, ?2)")?;
+            statement.execute(params![user_id, friend_id])?;
+            statement.execute(params![friend_id, user_id])?;
+            drop(statement);
+            transaction.commit()?;
+            Ok(true)
+        })?;
+        if !added {
+            return Err(MessageError::Conflict(format!("{} and {} can't be friends", user_id, friend_id)));
+        }
+        Ok(())
+    }
+
+    async fn remove_relationship(&self, user_id: &str, friend_id: &str) -> Result<bool> {
+        self.with_connection(|connection| {
+            let removed = connection.execute(
+                "DELETE FROM relationships
+                 WHERE (user_id = ?1 AND friend_id = ?2) OR (user_id = ?2 AND friend_id = ?1)",
+                params![user_id, friend_id],
+            )?;
+            Ok(removed > 0)
+        })
+    }
+
+    async fn block_user(&self, user_id: &str, blocked_id: &str) -> Result<()> {
+        self.with_connection(|connection| {
+            let transaction = connection.unchecked_transaction()?;
+            transaction.execute(
+                "DELETE FROM relationships
+                 WHERE (user_id = ?1 AND friend_id = ?2) OR (user_id = ?2 AND friend_id = ?1)",
+                params![user_id, blocked_id],
+            )?;
+            transaction.execute(
+                "INSERT OR IGNORE INTO blocks (user_id, blocked_id) VALUES (?1, ?2)",
+                params![user_id, blocked_id],
+            )?;
+            transaction.commit()
+        })
+    }
+
+    async fn match_candidates(&self, user_id: &str, limit: usize) -> Result<Vec<String>> {
+        self.with_connection(|connection| {
+            // Authors whose oldest unread message has waited longest come first
+            let mut statement = connection.prepare(&format!(
+                "SELECT user_id FROM messages
+                 WHERE unread AND NOT hidden AND recipient_id IS NULL AND user_id != ?1 AND {}
+                   AND user_id NOT IN (SELECT user_id FROM users WHERE suspended)
+                   AND user_id NOT IN (SELECT friend_id FROM relationships WHERE user_id = ?1)
+                   AND user_id NOT IN (SELECT blocked_id FROM blocks WHERE user_id = ?1)
+                   AND user_id NOT IN (SELECT user_id FROM blocks WHERE blocked_id = ?1)
+                 GROUP BY user_id ORDER BY MIN(created_at), user_id LIMIT ?3",
+                UNREAD_BY_READER
+            ))?;
+            let candidates = statement.query_map(params![user_id, user_id, limit as i64], |row| row.get(0))?;
+            candidates.collect()
+        })
+    }
+
+    async fn put_message(&self, message: Message) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute(
+                &format!("INSERT INTO messages ({}, unread) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 1)", MESSAGE_FIELDS),
+                params![
+                    message.user_id,
+                    message.message_id,
+                    message.message,
+                    message.recipient_id,
+                    message.reply_to,
+                    message.thread_id,
+                    message.created_at as i64,
+                    message.hidden
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    async fn message(&self, message_id: &str) -> Result<Option<Message>> {
+        self.with_connection(|connection| {
+            connection
+                .query_row(
+                    &format!("SELECT {} FROM messages WHERE message_id = ?1", MESSAGE_FIELDS),
+                    params![message_id],
+                    to_message,
+                )
+                .optional()
+        })
+    }
+
+    async fn recent_message_count(&self, author_id: &str, since: u64) -> Result<usize> {
+        self.with_connection(|connection| {
+            let count: i64 = connection.query_row(
+                "SELECT COUNT(*) FROM messages WHERE user_id = ?1 AND created_at >= ?2",
+                params![author_id, since as i64],
+                |row| row.get(0),
+            )?;
+            Ok(count as usize)
+        })
+    }
+
+    async fn hide_message(&self, message_id: &str) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute("UPDATE messages SET hidden = 1 WHERE message_id = ?1", params![message_id])?;
+            Ok(())
+        })
+    }
+
+    async fn unread_messages(&self, author_id: &str, reader_id: &str) -> Result<Vec<Message>> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM messages
+                 WHERE user_id = ?1 AND unread AND NOT hidden AND recipient_id IS NULL AND {}
+                 ORDER BY created_at, rowid",
+                MESSAGE_FIELDS, UNREAD_BY_READER
+            ))?;
+            let messages = statement.query_map(params![author_id, reader_id], to_message)?;
+            messages.collect()
+        })
+    }
+
+    async fn unread_count(&self, author_id: &str, reader_id: &str) -> Result<usize> {
+        self.with_connection(|connection| {
+            let count: i64 = connection.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM messages
+                     WHERE user_id = ?1 AND NOT hidden AND ((unread AND recipient_id IS NULL) OR recipient_id = ?2)
+                       AND {}",
+                    UNREAD_BY_READER
+                ),
+                params![author_id, reader_id],
+                |row| row.get(0),
+            )?;
+            Ok(count as usize)
+        })
+    }
+
+    async fn conversation(&self, user_id: &str, friend_id: &str) -> Result<Vec<Message>> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM messages
+                 WHERE ((user_id = ?1 AND recipient_id = ?2) OR (user_id = ?2 AND recipient_id = ?1)) AND NOT hidden
+                 ORDER BY created_at, rowid",
+                MESSAGE_FIELDS
+            ))?;
+            let messages = statement.query_map(params![user_id, friend_id], to_message)?;
+            messages.collect()
+        })
+    }
+
+    async fn mark_read(&self, reader_id: &str, message_id: &str) -> Result<()> {
+        let read_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0);
+        self.with_connection(|connection| {
+            connection.execute(
+                "INSERT OR IGNORE INTO read_receipts (reader_id, message_id, read_at) VALUES (?1, ?2, ?3)",
+                params![reader_id, message_id, read_at],
+            )?;
+            Ok(())
+        })
+    }
+
+    async fn put_report(&self, report: Report) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute(
+                &format!("INSERT INTO reports ({}, open) VALUES (?1, ?2, ?3, ?4, ?5, 1)", REPORT_FIELDS),
+                params![
+                    report.report_id,
+                    report.message_id,
+                    report.reporter_id,
+                    report.reason,
+                    report.created_at as i64
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    async fn open_reports(&self, limit: usize) -> Result<Vec<Report>> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare(&format!(
+                "SELECT {} FROM reports WHERE open ORDER BY created_at, rowid LIMIT ?1",
+                REPORT_FIELDS
+            ))?;
+            let reports = statement.query_map(params![limit as i64], to_report)?;
+            reports.collect()
+        })
+    }
+
+    async fn close_report(&self, report_id: &str) -> Result<bool> {
+        self.with_connection(|connection| {
+            let closed = connection.execute("UPDATE reports SET open = 0 WHERE report_id = ?1 AND open", params![report_id])?;
+            Ok(closed > 0)
+        })
+    }
+
+    async fn close_reports(&self, message_id: &str) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.execute("UPDATE reports SET open = 0 WHERE message_id = ?1 AND open", params![message_id])?;
+            Ok(())
+        })
+    }
+}
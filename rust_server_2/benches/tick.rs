@@ -0,0 +1,38 @@
+// Measures the cost of a game loop tick on maps of increasing size while the
+// amount of growing plants stays constant. With scheduled cell updates the
+// time per tick should stay flat as the map grows.
+//
+// Run with `cargo bench --bench tick`.
+use std::time::{Duration, Instant};
+
+use battista_server::map::map_generator::generate_sized_map;
+use battista_server::map::CellType;
+
+const TICKS: u64 = 10_000;
+// Plants are kept to the same first cells of the map, along its top rows,
+// so every map does the same work
+const ACTIVE_CELLS: usize = 256;
+
+fn main() {
+    println!("{:>10} {:>10} {:>14}", "plot side", "cells", "ns per tick");
+    for plot_side in [20, 60, 180, 540] {
+        let mut map = generate_sized_map(plot_side);
+        let cells = map.dimensions.size();
+
+        let start = Instant::now();
+        for tick in 0..TICKS {
+            // Replant a cell every tick so the schedule always has work queued
+            let index = (tick as usize * 7919) % ACTIVE_CELLS;
+            map.change_cell_type(index, CellType::Plant);
+            map.tick(Vec::new(), Vec::new(), Instant::now());
+        }
+        let elapsed: Duration = start.elapsed();
+
+        println!(
+            "{:>10} {:>10} {:>14}",
+            plot_side,
+            cells,
+            elapsed.as_nanos() / TICKS as u128
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{Clients, Result};
+use crate::audit::AuditLog;
+use crate::map::{self, CellType, Coords};
+use crate::map::map_responder::{admin_request, AdminError, AdminRequest, AdminResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use warp::{http::StatusCode, reply::json, reply::with_status, Reply};
+
+#[derive(Serialize, Debug)]
+struct ClientSummary<'a> {
+    id: &'a str,
+    user_id: &'a str,
+    topics: &'a [String],
+    connected: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SetCellRequest {
+    cell_type: CellType,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct TeleportRequest {
+    x: usize,
+    y: usize,
+}
+
+pub async fn list_clients_handler(_admin: String, clients: Clients) -> Result<impl Reply> {
+    let clients = clients.read().await;
+    let summaries: Vec<ClientSummary> = clients
+        .iter()
+        .map(|(id, client)| ClientSummary {
+            id,
+            user_id: &client.user_id,
+            topics: &client.topics,
+            connected: client.sender.is_some(),
+        })
+        .collect();
+    Ok(json(&summaries))
+}
+
+pub async fn list_players_handler(_admin: String, map_sender: map::MapSender) -> Result<impl Reply> {
+    Ok(respond(admin_request(map_sender, AdminRequest::ListPlayers).await))
+}
+
+pub async fn get_cell_handler(x: usize, y: usize, _admin: String, map_sender: map::MapSender) -> Result<impl Reply> {
+    let request = AdminRequest::GetCell(Coords { x, y });
+    Ok(respond(admin_request(map_sender, request).await))
+}
+
+pub async fn set_cell_handler(
+        x: usize,
+        y: usize,
+        admin: String,
+        body: SetCellRequest,
+        map_sender: map::MapSender,
+        audit_log: Arc<AuditLog>,
+    ) -> Result<impl Reply> {
+    let request = AdminRequest::SetCell(Coords { x, y }, body.cell_type.clone());
+    let response = admin_request(map_sender, request).await;
+    audit(&audit_log, &admin, "set_cell", json!({"x": x, "y": y, "cell_type": body.cell_type}), &response);
+    Ok(respond(response))
+}
+
+pub async fn teleport_handler(
+        user_id: String,
+        admin: String,
+        body: TeleportRequest,
+        map_sender: map::MapSender,
+        audit_log: Arc<AuditLog>,
+    ) -> Result<impl Reply> {
+    let request = AdminRequest::Teleport(user_id.clone(), Coords { x: body.x, y: body.y });
+    let response = admin_request(map_sender, request).await;
+    audit(&audit_log, &admin, "teleport", json!({"user_id": user_id, "x": body.x, "y": body.y}), &response);
+    Ok(respond(response))
+}
+
+pub async fn kick_handler(
+        user_id: String,
+        admin: String,
+        map_sender: map::MapSender,
+        audit_log: Arc<AuditLog>,
+    ) -> Result<impl Reply> {
+    let response = admin_request(map_sender, AdminRequest::Kick(user_id.clone())).await;
+    audit(&audit_log, &admin, "kick", json!({"user_id": user_id}), &response);
+    Ok(respond(response))
+}
+
+pub async fn reset_plot_handler(
+        index: usize,
+        admin: String,
+        map_sender: map::MapSender,
+        audit_log: Arc<AuditLog>,
+    ) -> Result<impl Reply> {
+    let response = admin_request(map_sender, AdminRequest::ResetPlot(index)).await;
+    audit(&audit_log, &admin, "reset_plot", json!({"index": index}), &response);
+    Ok(respond(response))
+}
+
+pub async fn snapshot_handler(
+        admin: String,
+        map_sender: map::MapSender,
+        audit_log: Arc<AuditLog>,
+    ) -> Result<impl Reply> {
+    let response = admin_request(map_sender, AdminRequest::Snapshot).await;
+    audit(&audit_log, &admin, "snapshot", json!({}), &response);
+    Ok(respond(response))
+}
+
+// Mutations are recorded whether or not they succeeded
+fn audit(audit_log: &AuditLog, admin: &str, action: &str, request: serde_json::Value, response: &AdminResponse) {
+    let error = response.as_ref().err().map(error_message);
+    audit_log.record(admin, action, json!({"request": request, "error": error}));
+}
+
+fn respond(response: AdminResponse) -> warp::reply::WithStatus<warp::reply::Json> {
+    match response {
+        Ok(value) => with_status(json(&value), StatusCode::OK),
+        Err(e) => {
+            let status = match e {
+                AdminError::NotFound(_) => StatusCode::NOT_FOUND,
+                AdminError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            with_status(json(&json!({"error": error_message(&e)})), status)
+        }
+    }
+}
+
+fn error_message(error: &AdminError) -> &str {
+    match error {
+        AdminError::NotFound(message) | AdminError::Failed(message) => message,
+    }
+}
@@ -0,0 +1,44 @@
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+// Append-only record of admin actions, one JSON object per line
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+#[derive(Serialize, Debug)]
+struct AuditEntry<'a, T: Serialize> {
+    timestamp: u64,
+    admin: &'a str,
+    action: &'a str,
+    details: T,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> std::io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record<T: Serialize>(&self, admin: &str, action: &str, details: T) {
+        let entry = AuditEntry {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            admin,
+            action,
+            details,
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            error!(error = %e, "could not write to the audit log");
+        }
+    }
+}
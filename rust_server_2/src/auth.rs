@@ -0,0 +1,159 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use warp::reject::{Reject, Rejection};
+
+// Comma separated `name:key` pairs, e.g. `alice:s3cret,ops-bot:an0ther`
+pub const ADMIN_KEYS_VAR: &str = "BATTISTA_ADMIN_KEYS";
+// The same secret message_server signs login tokens with (MESSAGE_TOKEN_KEY)
+pub const PLAYER_TOKEN_KEY_VAR: &str = "BATTISTA_PLAYER_TOKEN_KEY";
+// Matches the minimum message_server enforces for the same key
+const MIN_PLAYER_TOKEN_KEY_LENGTH: usize = 32;
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+// API keys allowed to use the admin endpoints, and who each belongs to
+#[derive(Debug, Clone, Default)]
+pub struct AdminKeys {
+    keys: HashMap<String, String>,
+}
+
+impl AdminKeys {
+    pub fn from_env() -> AdminKeys {
+        let mut keys = HashMap::new();
+        if let Ok(value) = env::var(ADMIN_KEYS_VAR) {
+            for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+                match pair.split_once(':') {
+                    Some((name, key)) if !name.is_empty() && !key.is_empty() => {
+                        keys.insert(name.to_string(), key.to_string());
+                    }
+                    _ => warn!("ignoring malformed entry in {}, expected name:key", ADMIN_KEYS_VAR),
+                }
+            }
+        }
+        AdminKeys { keys }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    // Who the `Authorization: Bearer <key>` header belongs to
+    pub fn authenticate(&self, authorization: Option<String>) -> Result<String, Rejection> {
+        let key = match authorization.as_deref().and_then(|header| header.strip_prefix("Bearer ")) {
+            Some(key) => key,
+            None => return Err(warp::reject::custom(Unauthorized)),
+        };
+        // Compare against every key so the time taken doesn't reveal which one nearly matched
+        let mut admin = None;
+        for (name, admin_key) in &self.keys {
+            if constant_time_eq(admin_key.as_bytes(), key.as_bytes()) {
+                admin = Some(name.clone());
+            }
+        }
+        admin.ok_or_else(|| warp::reject::custom(Unauthorized))
+    }
+}
+
+// Checks the login tokens message_server hands out, so players are known by
+// their message_server user id. A token is `<user_id>.<expires_at>.<signature>`,
+// expires_at in seconds since the Unix epoch and the signature the hex
+// HMAC-SHA256 of `<user_id>.<expires_at>`.
+#[derive(Clone)]
+pub struct PlayerTokens {
+    key: Vec<u8>,
+}
+
+impl PlayerTokens {
+    pub fn from_env() -> Result<PlayerTokens, String> {
+        let key = env::var(PLAYER_TOKEN_KEY_VAR).map_err(|_| format!("{} is not set", PLAYER_TOKEN_KEY_VAR))?;
+        PlayerTokens::new(key.into_bytes())
+    }
+
+    // Short keys make the signatures easy to forge, so they are refused outright
+    pub fn new(key: Vec<u8>) -> Result<PlayerTokens, String> {
+        if key.len() < MIN_PLAYER_TOKEN_KEY_LENGTH {
+            return Err(format!("{} should be at least {} bytes", PLAYER_TOKEN_KEY_VAR, MIN_PLAYER_TOKEN_KEY_LENGTH));
+        }
+        Ok(PlayerTokens { key })
+    }
+
+    // The user id the token was issued to
+    pub fn verify(&self, token: &str) -> Result<String, Rejection> {
+        let unauthorized = || warp::reject::custom(Unauthorized);
+        let (claims, signature) = token.rsplit_once('.').ok_or_else(unauthorized)?;
+        let signature = unhex(signature).ok_or_else(unauthorized)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(claims.as_bytes());
+        // verify_slice compares in constant time
+        mac.verify_slice(&signature).map_err(|_| unauthorized())?;
+        let (user_id, expires_at) = claims.rsplit_once('.').ok_or_else(unauthorized)?;
+        let expires_at: u64 = expires_at.parse().map_err(|_| unauthorized())?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        if user_id.is_empty() || expires_at <= now {
+            return Err(unauthorized());
+        }
+        Ok(user_id.to_string())
+    }
+}
+
+impl std::fmt::Debug for PlayerTokens {
+    // Keeps the key out of logs
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PlayerTokens").field("key", &"...").finish()
+    }
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn sign(claims: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(KEY).unwrap();
+        mac.update(claims.as_bytes());
+        let signature: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}.{}", claims, signature)
+    }
+
+    #[test]
+    fn short_keys_are_refused() {
+        assert!(PlayerTokens::new(KEY[..31].to_vec()).is_err());
+        assert!(PlayerTokens::new(KEY.to_vec()).is_ok());
+    }
+
+    #[test]
+    fn tokens_name_the_user_they_were_issued_to() {
+        let tokens = PlayerTokens::new(KEY.to_vec()).unwrap();
+        assert_eq!(tokens.verify(&sign("user-1.99999999999")).unwrap(), "user-1");
+        // Expired
+        assert!(tokens.verify(&sign("user-1.1")).is_err());
+        // Signed with another key
+        let other = PlayerTokens::new(b"fedcba9876543210fedcba9876543210".to_vec()).unwrap();
+        assert!(other.verify(&sign("user-1.99999999999")).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Longest message, in characters, anyone may send
+pub const MAX_CHAT_LENGTH: usize = 280;
+// How many cells away a player can be and still hear someone talking
+pub const CHAT_RADIUS: usize = 10;
+// Players may send a burst of this many messages, then one per refill interval
+const BURST: u32 = 5;
+const REFILL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Chat as sent by the client, e.g. `{"type": "chat", "text": "hello"}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatRequest {
+    // Heard by everyone nearby or in the same plot
+    Chat { text: String },
+    // Only delivered to the given player, wherever they are
+    Whisper { user_id: String, text: String },
+}
+
+#[derive(Debug)]
+pub struct ChatMessage {
+    pub from: String,
+    pub request: ChatRequest,
+}
+
+// Sent to every recipient of a message
+#[derive(Serialize, Debug)]
+pub struct ChatDelivery<'a> {
+    #[serde(rename = "type")]
+    pub message_type: &'static str,
+    pub from: &'a str,
+    pub text: &'a str,
+    pub whisper: bool,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ChatError {
+    Empty,
+    TooLong,
+    RateLimited,
+    // Whispered to a player who isn't connected
+    NotOnline,
+}
+
+impl ChatError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ChatError::Empty => "message is empty",
+            ChatError::TooLong => "message is too long",
+            ChatError::RateLimited => "sending messages too quickly",
+            ChatError::NotOnline => "that player is not online",
+        }
+    }
+}
+
+// Token bucket per player
+#[derive(Debug)]
+struct Allowance {
+    tokens: u32,
+    last_refill: Instant,
+}
+
+// Decides which chat messages are allowed through
+#[derive(Debug, Default)]
+pub struct ChatLimiter {
+    allowances: HashMap<String, Allowance>,
+}
+
+impl ChatLimiter {
+    pub fn new() -> ChatLimiter {
+        ChatLimiter {
+            allowances: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, message: &ChatMessage, now: Instant) -> Result<(), ChatError> {
+        let text = match &message.request {
+            ChatRequest::Chat { text } => text,
+            ChatRequest::Whisper { text, .. } => text,
+        };
+        if text.trim().is_empty() {
+            return Err(ChatError::Empty);
+        }
+        if text.chars().count() > MAX_CHAT_LENGTH {
+            return Err(ChatError::TooLong);
+        }
+
+        let allowance = self.allowances.entry(message.from.clone()).or_insert(Allowance {
+            tokens: BURST,
+            last_refill: now,
+        });
+        let refills = (now.duration_since(allowance.last_refill).as_millis() / REFILL_INTERVAL.as_millis()) as u32;
+        if refills > 0 {
+            allowance.tokens = (allowance.tokens + refills).min(BURST);
+            allowance.last_refill += REFILL_INTERVAL * refills;
+        }
+        if allowance.tokens == 0 {
+            return Err(ChatError::RateLimited);
+        }
+        allowance.tokens -= 1;
+        Ok(())
+    }
+}
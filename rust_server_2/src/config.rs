@@ -0,0 +1,425 @@
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+use warp::http::Uri;
+
+// Settings are layered: defaults, then the config file, then the
+// environment, then command line flags
+pub const CONFIG_PATH_VAR: &str = "BATTISTA_CONFIG";
+const ENV_PREFIX: &str = "BATTISTA_";
+const OPTIONS: &[&str] = &[
+    "bind", "public_url", "static_dir", "audit_log_path", "channel_capacity", "cors_origins",
+    "tick_ms", "plot_side", "plots_path", "snapshot_path", "tls_cert_path", "tls_key_path",
+    "log_filter", "log_format",
+];
+
+pub const USAGE: &str = "\
+Usage: battista_server [OPTIONS]
+
+Options:
+    --config <path>            TOML config file (env BATTISTA_CONFIG)
+    --bind <addr>              Address to listen on, e.g. 127.0.0.1:8000
+    --public-url <url>         Base URL clients reach the websocket on, e.g. wss://example.com
+    --static-dir <path>        Directory served under /static
+    --audit-log-path <path>    Where admin actions are recorded
+    --channel-capacity <n>     Map requests queued before senders wait
+    --cors-origins <list>      Comma separated origins allowed by CORS, any if empty
+    --tick-ms <ms>             Milliseconds per game tick
+    --plot-side <n>            Cells along the side of each plot
+    --plots-path <path>        Where plot claims are saved
+    --snapshot-path <path>     Where world snapshots are saved
+    --tls-cert-path <path>     PEM certificate chain, serves HTTPS and wss:// when set with a key
+    --tls-key-path <path>      PEM private key, reloaded along with the certificate on SIGHUP
+    --log-filter <filter>      Which logs to keep, e.g. info or battista_server=debug,warn
+    --log-format <format>      text, or json for log shipping
+    -h, --help                 Print this message
+
+Every option can also be set with an environment variable, e.g. BATTISTA_TICK_MS=50";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    // Clients are handed `{public_url}/ws/{id}`, defaults to `ws://{bind}`
+    // or `wss://{bind}` with TLS
+    pub public_url: Option<String>,
+    pub static_dir: PathBuf,
+    pub audit_log_path: PathBuf,
+    pub channel_capacity: usize,
+    pub cors_origins: Vec<String>,
+    pub map: MapConfig,
+    pub tls: TlsConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapConfig {
+    pub tick_ms: u64,
+    pub plot_side: usize,
+    pub plots_path: PathBuf,
+    pub snapshot_path: PathBuf,
+}
+
+// TLS is only terminated here when both paths are given
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // Directives in `RUST_LOG` syntax
+    pub filter: String,
+    pub format: LogFormat,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    HelpRequested,
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    UnknownOption(String),
+    MissingValue(String),
+    Invalid(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::HelpRequested => write!(f, "{}", USAGE),
+            ConfigError::Read(path, e) => write!(f, "could not read config {:?}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse config {:?}: {}", path, e),
+            ConfigError::UnknownOption(option) => write!(f, "unknown option {}\n\n{}", option, USAGE),
+            ConfigError::MissingValue(option) => write!(f, "{} needs a value", option),
+            ConfigError::Invalid(key, reason) => write!(f, "invalid {}: {}", key, reason),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: SocketAddr::from(([127, 0, 0, 1], 8000)),
+            public_url: None,
+            static_dir: PathBuf::from("www/static"),
+            audit_log_path: PathBuf::from("audit.log"),
+            channel_capacity: 32,
+            cors_origins: Vec::new(),
+            map: MapConfig::default(),
+            tls: TlsConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            filter: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for MapConfig {
+    fn default() -> Self {
+        MapConfig {
+            tick_ms: 33,
+            plot_side: 20,
+            plots_path: PathBuf::from("plots.json"),
+            snapshot_path: PathBuf::from("world.json"),
+        }
+    }
+}
+
+impl Config {
+    // Builds the config from the process's environment and arguments
+    pub fn load() -> Result<Config, ConfigError> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let flags = parse_args(&args)?;
+
+        let path = flags.iter()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| std::env::var(CONFIG_PATH_VAR).ok());
+        let mut config = match path {
+            Some(path) => Config::from_file(PathBuf::from(path))?,
+            None => Config::default(),
+        };
+
+        for (var, value) in std::env::vars() {
+            if var == CONFIG_PATH_VAR {
+                continue;
+            }
+            if let Some(key) = var.strip_prefix(ENV_PREFIX) {
+                // Other settings such as the admin keys share the prefix
+                let key = key.to_lowercase();
+                if OPTIONS.contains(&key.as_str()) {
+                    config.set(&key, &value)?;
+                }
+            }
+        }
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: PathBuf) -> Result<Config, ConfigError> {
+        let contents = fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path, e.to_string()))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "bind" => self.bind = parse(key, value)?,
+            "public_url" => self.public_url = Some(value.to_string()).filter(|url| !url.is_empty()),
+            "static_dir" => self.static_dir = PathBuf::from(value),
+            "audit_log_path" => self.audit_log_path = PathBuf::from(value),
+            "channel_capacity" => self.channel_capacity = parse(key, value)?,
+            "cors_origins" => {
+                self.cors_origins = value.split(',')
+                    .map(str::trim)
+                    .filter(|origin| !origin.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            "tick_ms" => self.map.tick_ms = parse(key, value)?,
+            "plot_side" => self.map.plot_side = parse(key, value)?,
+            "plots_path" => self.map.plots_path = PathBuf::from(value),
+            "snapshot_path" => self.map.snapshot_path = PathBuf::from(value),
+            "tls_cert_path" => self.tls.cert_path = Some(PathBuf::from(value)).filter(|_| !value.is_empty()),
+            "tls_key_path" => self.tls.key_path = Some(PathBuf::from(value)).filter(|_| !value.is_empty()),
+            "log_filter" => self.log.filter = value.to_string(),
+            "log_format" => {
+                self.log.format = match value {
+                    "text" => LogFormat::Text,
+                    "json" => LogFormat::Json,
+                    _ => return Err(ConfigError::Invalid(key.to_string(), format!("{:?} is not text or json", value))),
+                }
+            }
+            _ => return Err(ConfigError::UnknownOption(format!("--{}", key.replace('_', "-")))),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, reason: String| Err(ConfigError::Invalid(key.to_string(), reason));
+
+        match &self.public_url {
+            Some(url) => {
+                let uri = url.parse::<Uri>().map_err(|e| ConfigError::Invalid("public_url".to_string(), e.to_string()))?;
+                if !matches!(uri.scheme_str(), Some("ws") | Some("wss")) || uri.host().is_none() {
+                    return invalid("public_url", format!("{} should look like ws://host or wss://host", url));
+                }
+                if self.tls.is_enabled() && uri.scheme_str() != Some("wss") {
+                    return invalid("public_url", format!("{} should use wss:// when TLS is enabled", url));
+                }
+            }
+            None => {
+                if self.bind.ip().is_unspecified() {
+                    return invalid("public_url", format!("must be set when binding to {}", self.bind));
+                }
+            }
+        }
+        for origin in &self.cors_origins {
+            let valid = origin.parse::<Uri>().ok()
+                .filter(|uri| matches!(uri.scheme_str(), Some("http") | Some("https")))
+                .filter(|uri| uri.host().is_some() && !origin.ends_with('/'));
+            if valid.is_none() {
+                return invalid("cors_origins", format!("{} should look like https://host", origin));
+            }
+        }
+        match (&self.tls.cert_path, &self.tls.key_path) {
+            (Some(_), None) => return invalid("tls_key_path", "must be set along with tls_cert_path".to_string()),
+            (None, Some(_)) => return invalid("tls_cert_path", "must be set along with tls_key_path".to_string()),
+            _ => {}
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log.filter) {
+            return invalid("log_filter", format!("{:?}: {}", self.log.filter, e));
+        }
+        if self.channel_capacity == 0 {
+            return invalid("channel_capacity", "must be at least 1".to_string());
+        }
+        if !(1..=1000).contains(&self.map.tick_ms) {
+            return invalid("tick_ms", format!("{} is not between 1 and 1000", self.map.tick_ms));
+        }
+        // Plots need room for a door in each wall and an animal in each corner
+        if !(5..=1000).contains(&self.map.plot_side) {
+            return invalid("plot_side", format!("{} is not between 5 and 1000", self.map.plot_side));
+        }
+        if !self.static_dir.is_dir() {
+            return invalid("static_dir", format!("{:?} is not a directory", self.static_dir));
+        }
+        Ok(())
+    }
+
+    pub fn ws_url(&self, id: &str) -> String {
+        let base = match &self.public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None if self.tls.is_enabled() => format!("wss://{}", self.bind),
+            None => format!("ws://{}", self.bind),
+        };
+        format!("{}/ws/{}", base, id)
+    }
+}
+
+impl TlsConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.cert_path.is_some() && self.key_path.is_some()
+    }
+}
+
+impl MapConfig {
+    pub fn tick_duration(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+}
+
+// Turns `--tick-ms 50` and `--tick-ms=50` into `("tick_ms", "50")`
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Err(ConfigError::HelpRequested);
+        }
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => return Err(ConfigError::UnknownOption(arg.clone())),
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, value.to_string()),
+            None => match args.next() {
+                Some(value) => (flag, value.clone()),
+                None => return Err(ConfigError::MissingValue(arg.clone())),
+            },
+        };
+        flags.push((name.replace('-', "_"), value));
+    }
+    Ok(flags)
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|e: T::Err| ConfigError::Invalid(key.to_string(), format!("{:?}: {}", value, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = Config::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.map.tick_duration(), Duration::from_millis(33));
+        assert_eq!(config.ws_url("abc"), "ws://127.0.0.1:8000/ws/abc");
+    }
+
+    #[test]
+    fn flags_take_their_value_either_way() {
+        let flags = parse_args(&args(&["--tick-ms", "50", "--plot-side=30"])).unwrap();
+        assert_eq!(flags, vec![
+            ("tick_ms".to_string(), "50".to_string()),
+            ("plot_side".to_string(), "30".to_string()),
+        ]);
+        assert!(matches!(parse_args(&args(&["--tick-ms"])), Err(ConfigError::MissingValue(_))));
+        assert!(matches!(parse_args(&args(&["tick-ms"])), Err(ConfigError::UnknownOption(_))));
+        assert!(matches!(parse_args(&args(&["-h"])), Err(ConfigError::HelpRequested)));
+    }
+
+    #[test]
+    fn settings_are_parsed_by_key() {
+        let mut config = Config::default();
+        config.set("tick_ms", "50").unwrap();
+        config.set("cors_origins", "https://a.example, ,https://b.example").unwrap();
+        config.set("log_format", "json").unwrap();
+        config.set("tls_cert_path", "").unwrap();
+        assert_eq!(config.map.tick_ms, 50);
+        assert_eq!(config.cors_origins, vec!["https://a.example", "https://b.example"]);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert!(config.tls.cert_path.is_none());
+
+        assert!(matches!(config.set("tick_ms", "soon"), Err(ConfigError::Invalid(..))));
+        assert!(matches!(config.set("log_format", "xml"), Err(ConfigError::Invalid(..))));
+        assert!(matches!(config.set("colour", "blue"), Err(ConfigError::UnknownOption(_))));
+    }
+
+    #[test]
+    fn files_fill_in_from_the_defaults() {
+        let path = std::env::temp_dir().join(format!("battista_config_{}.toml", std::process::id()));
+        fs::write(&path, "channel_capacity = 8\n\n[map]\nplot_side = 40\n").unwrap();
+        let config = Config::from_file(path.clone());
+        fs::write(&path, "[map]\nplot_sides = 40\n").unwrap();
+        let misspelt = Config::from_file(path.clone());
+        let _ = fs::remove_file(&path);
+
+        let config = config.unwrap();
+        assert_eq!(config.channel_capacity, 8);
+        assert_eq!(config.map.plot_side, 40);
+        assert_eq!(config.map.tick_ms, MapConfig::default().tick_ms);
+        assert!(matches!(misspelt, Err(ConfigError::Parse(..))));
+    }
+
+    #[test]
+    fn validation_rejects_unusable_settings() {
+        let invalid_key = |config: Config| match config.validate() {
+            Err(ConfigError::Invalid(key, _)) => key,
+            other => panic!("expected an invalid setting, got {:?}", other),
+        };
+
+        let mut config = Config::default();
+        config.map.plot_side = 4;
+        assert_eq!(invalid_key(config), "plot_side");
+
+        let config = Config { bind: SocketAddr::from(([0, 0, 0, 0], 8000)), ..Config::default() };
+        assert_eq!(invalid_key(config), "public_url");
+
+        let mut config = Config::default();
+        config.tls.cert_path = Some(PathBuf::from("cert.pem"));
+        assert_eq!(invalid_key(config), "tls_key_path");
+
+        let config = Config {
+            public_url: Some("ws://example.com".to_string()),
+            tls: TlsConfig {
+                cert_path: Some(PathBuf::from("cert.pem")),
+                key_path: Some(PathBuf::from("key.pem")),
+            },
+            ..Config::default()
+        };
+        assert_eq!(invalid_key(config), "public_url");
+
+        let config = Config { cors_origins: vec!["https://example.com/".to_string()], ..Config::default() };
+        assert_eq!(invalid_key(config), "cors_origins");
+    }
+
+    #[test]
+    fn public_urls_replace_the_bind_address() {
+        let config = Config { public_url: Some("wss://example.com/".to_string()), ..Config::default() };
+        assert_eq!(config.ws_url("abc"), "wss://example.com/ws/abc");
+    }
+}
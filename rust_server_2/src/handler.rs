@@ -1,83 +1,180 @@
-use crate::{ws, Client, Clients, Result};
+use crate::{topics, ws, Client, Clients, Result};
+use crate::audit::AuditLog;
+use crate::auth::{PlayerTokens, Unauthorized};
+use crate::config::Config;
+use crate::metrics::Metrics;
 use crate::map;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::info;
 use uuid::Uuid;
-use warp::{http::StatusCode, reply::json, ws::Message, Reply};
+use warp::{http::StatusCode, reply::json, reply::with_status, ws::Message, Rejection, Reply};
 
 #[derive(Deserialize, Debug)]
 pub struct RegisterRequest {
-    user_id: usize,
+    // The token from logging in to message_server
+    token: String,
+    // Topic patterns to start out subscribed to instead of the defaults
+    topics: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct RegisterResponse{
     url: String,
     player_position: map::Coords,
-    explored_cells: Vec<map::Cell>,
+    explored_cells: Vec<map::CellView>,
     width: usize,
-    height: usize
+    height: usize,
+    plots: Vec<map::plots::Plot>,
+    npcs: Vec<map::npcs::Npc>,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct Event {
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NoticeLevel {
+    #[default]
+    Info,
+    Warning,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Notice {
+    #[serde(default)]
+    level: NoticeLevel,
+    title: Option<String>,
+    body: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PublishRequest {
     topic: String,
-    user_id: Option<usize>,
-    message: String,
+    user_id: Option<String>,
+    notice: Notice,
+}
+
+#[derive(Serialize, Debug)]
+struct PublishAudit<'a> {
+    #[serde(flatten)]
+    request: &'a PublishRequest,
+    recipients: usize,
 }
 
-pub async fn publish_handler(body: Event, clients: Clients) -> Result<impl Reply> {
+pub async fn publish_handler(
+        admin: String,
+        body: PublishRequest,
+        clients: Clients,
+        audit_log: Arc<AuditLog>,
+    ) -> Result<impl Reply> {
+    let message = json!(
+        {
+            "type": "notice",
+            "topic": &body.topic,
+            "notice": &body.notice
+        }
+    ).to_string();
+
+    let mut recipients = 0;
     clients
         .read()
         .await
         .iter()
-        .filter(|(_, client)| match body.user_id {
-            Some(v) => client.user_id == v,
+        .filter(|(_, client)| match &body.user_id {
+            Some(v) => &client.user_id == v,
             None => true,
         })
-        .filter(|(_, client)| client.topics.contains(&body.topic))
+        .filter(|(_, client)| client.subscribed_to(&body.topic))
         .for_each(|(_, client)| {
             if let Some(sender) = &client.sender {
-                let _ = sender.send(Ok(Message::text(body.message.clone())));
+                if sender.send(Ok(Message::text(message.clone()))).is_ok() {
+                    recipients += 1;
+                }
             }
         });
 
+    audit_log.record(&admin, "publish", PublishAudit { request: &body, recipients });
     Ok(StatusCode::OK)
 }
 
-pub async fn register_handler(body: RegisterRequest, clients: Clients, map_sender: map::MapSender) -> Result<impl Reply> {
-    println!("Registering user");
-    let user_id = body.user_id;
+// Turns rejections raised by our own filters into responses, leaving the rest to warp
+pub async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        return Ok(with_status(json(&"unauthorized"), StatusCode::UNAUTHORIZED));
+    }
+    Err(err)
+}
+
+pub async fn register_handler(
+        body: RegisterRequest,
+        player_tokens: PlayerTokens,
+        clients: Clients,
+        map_sender: map::MapSender,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+    ) -> Result<impl Reply> {
+    let user_id = player_tokens.verify(&body.token)?;
     let uuid = Uuid::new_v4().simple().to_string();
 
 
-    register_client(uuid.clone(), user_id, clients).await;
+    let mut subscriptions = topics::default_subscriptions();
+    if let Some(requested) = body.topics {
+        subscriptions.clear();
+        if topics::subscribe(&mut subscriptions, &requested).is_err() {
+            return Ok(with_status(json(&"invalid topics"), StatusCode::BAD_REQUEST));
+        }
+    }
+
+    register_client(uuid.clone(), user_id.clone(), subscriptions, clients).await;
+    info!(user_id = %user_id, session = %uuid, "registered client");
+    metrics.registrations.inc();
     
     let response = map::map_responder::register_player(
         map_sender,
-        user_id.to_string()
+        user_id
     ).await;
-    let (width, height) = map::get_dimensions();
 
-    Ok(json(&RegisterResponse {
-        url: format!("ws://127.0.0.1:8000/ws/{}", uuid),
+    Ok(with_status(json(&RegisterResponse {
+        url: config.ws_url(&uuid),
         player_position: response.player_coords.clone(),
         explored_cells: response.explored_cells,
-        height: height,
-        width: width
-    }))
+        height: response.dimensions.height,
+        width: response.dimensions.width,
+        plots: response.plots,
+        npcs: response.npcs,
+    }), StatusCode::OK))
 }
 
-async fn register_client(id: String, user_id: usize, clients: Clients) {
+async fn register_client(id: String, user_id: String, topics: Vec<String>, clients: Clients) {
     clients.write().await.insert(
         id,
         Client {
             user_id,
-            topics: vec![String::from("cats")],
+            topics,
             sender: None,
         },
     );
 }
 
+pub async fn subscribe_handler(id: String, body: ws::TopicsRequest, clients: Clients) -> Result<impl Reply> {
+    match clients.write().await.get_mut(&id) {
+        Some(client) => match topics::subscribe(&mut client.topics, &body.topics) {
+            Ok(()) => Ok(with_status(json(&client.topics), StatusCode::OK)),
+            Err(reason) => Ok(with_status(json(&reason), StatusCode::BAD_REQUEST)),
+        },
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+pub async fn unsubscribe_handler(id: String, body: ws::TopicsRequest, clients: Clients) -> Result<impl Reply> {
+    match clients.write().await.get_mut(&id) {
+        Some(client) => {
+            topics::unsubscribe(&mut client.topics, &body.topics);
+            Ok(json(&client.topics))
+        }
+        None => Err(warp::reject::not_found()),
+    }
+}
+
 pub async fn unregister_handler(id: String, clients: Clients) -> Result<impl Reply> {
     clients.write().await.remove(&id);
     Ok(StatusCode::OK)
@@ -88,10 +185,11 @@ pub async fn ws_handler(
         id: String,
         clients: Clients,
         tx: map::MapSender,
+        metrics: Arc<Metrics>,
     ) -> Result<impl Reply> {
     let client = clients.read().await.get(&id).cloned();
     match client {
-        Some(client) => Ok(ws.on_upgrade(move |socket| ws::client_connection(socket, id, clients, tx, client))),
+        Some(client) => Ok(ws.on_upgrade(move |socket| ws::client_connection(socket, id, clients, tx, client, metrics))),
         None => Err(warp::reject::not_found()),
     }
 }
@@ -100,6 +198,10 @@ pub async fn test_handler() -> Result<impl Reply> {
     Ok(String::from("test"))
 }
 
+pub async fn metrics_handler(metrics: Arc<Metrics>) -> Result<impl Reply> {
+    Ok(warp::reply::with_header(metrics.encode(), "content-type", "text/plain; version=0.0.4"))
+}
+
 pub async fn health_handler() -> Result<impl Reply> {
     Ok(StatusCode::OK)
 }
\ No newline at end of file
@@ -0,0 +1,37 @@
+// Explicit returns are kept where the original code used them
+#![allow(clippy::needless_return)]
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, RwLock};
+use warp::{ws::Message, Rejection};
+
+pub mod handler;
+pub mod ws;
+pub mod map;
+pub mod chat;
+pub mod topics;
+pub mod auth;
+pub mod audit;
+pub mod admin;
+pub mod config;
+pub mod tls;
+pub mod metrics;
+
+type Result<T> = std::result::Result<T, Rejection>;
+pub type Clients = Arc<RwLock<HashMap<String, Client>>>;
+pub type ClientSender = mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>;
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    // The message_server user id, also the key of the player on the map
+    pub user_id: String,
+    pub topics: Vec<String>,
+    pub sender: Option<ClientSender>,
+}
+
+impl Client {
+    pub fn subscribed_to(&self, topic: &str) -> bool {
+        self.topics.iter().any(|pattern| topics::matches(pattern, topic))
+    }
+}
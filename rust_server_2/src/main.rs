@@ -1,41 +1,85 @@
 use std::collections::HashMap;
 use std::convert::Infallible;
-use std::sync::{Arc, Mutex};
+use std::io::IsTerminal;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::{mpsc, RwLock};
-use warp::{ws::Message, Filter, Rejection};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+use warp::Filter;
 
-mod handler;
-mod ws;
-mod map;
+use battista_server::audit::AuditLog;
+use battista_server::auth::{self, AdminKeys, PlayerTokens};
+use battista_server::config::{Config, ConfigError, LogConfig, LogFormat};
+use battista_server::metrics::Metrics;
+use battista_server::{admin, handler, map, tls, Clients};
 
-type Result<T> = std::result::Result<T, Rejection>;
-type Clients = Arc<RwLock<HashMap<String, Client>>>;
-
-#[derive(Debug, Clone)]
-pub struct Client {
-    pub user_id: usize,
-    pub topics: Vec<String>,
-    pub sender: Option<mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>>,
-}
+// How long clients get to close their sockets when the server stops
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(ConfigError::HelpRequested) => {
+            println!("{}", ConfigError::HelpRequested);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    init_logging(&config.log);
+
+    // Without the key no player could ever register
+    let player_tokens = match PlayerTokens::from_env() {
+        Ok(player_tokens) => player_tokens,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
     let clients: Clients = Arc::new(RwLock::new(HashMap::new()));
 
-    let (sender, receiver) = mpsc::channel(32);
+    let metrics = Arc::new(Metrics::new());
+
+    let (sender, receiver) = mpsc::channel(config.channel_capacity);
     let cclients = clients.clone();
+    let map_config = config.map.clone();
+    let cmetrics = metrics.clone();
     tokio::spawn(async move{
-        map::map_responder::game_loop(receiver, cclients).await;
+        map::map_responder::game_loop(receiver, cclients, map_config, cmetrics).await;
     });
 
+    let admin_keys = AdminKeys::from_env();
+    if admin_keys.is_empty() {
+        warn!("{} is not set, admin endpoints will refuse every request", auth::ADMIN_KEYS_VAR);
+    }
+    let audit_log = Arc::new(AuditLog::open(&config.audit_log_path).expect("could not open the audit log"));
+
     let health_route = warp::path!("health").and_then(handler::health_handler);
 
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_metrics(metrics.clone()))
+        .and_then(handler::metrics_handler);
+
     let register = warp::path("register");
     let register_routes = register
         .and(warp::post())
         .and(warp::body::json())
+        .and(with_player_tokens(player_tokens))
         .and(with_clients(clients.clone()))
         .and(with_sender(sender.clone()))
+        .and(with_config(config.clone()))
+        .and(with_metrics(metrics.clone()))
         .and_then(handler::register_handler)
         .or(register
             .and(warp::delete())
@@ -44,15 +88,79 @@ async fn main() {
             .and_then(handler::unregister_handler));
 
     let publish = warp::path!("publish")
+        .and(warp::post())
+        .and(with_admin(admin_keys.clone()))
         .and(warp::body::json())
         .and(with_clients(clients.clone()))
+        .and(with_audit_log(audit_log.clone()))
         .and_then(handler::publish_handler);
 
+    let admin_routes = warp::path!("admin" / "clients")
+        .and(warp::get())
+        .and(with_admin(admin_keys.clone()))
+        .and(with_clients(clients.clone()))
+        .and_then(admin::list_clients_handler)
+        .or(warp::path!("admin" / "players")
+            .and(warp::get())
+            .and(with_admin(admin_keys.clone()))
+            .and(with_sender(sender.clone()))
+            .and_then(admin::list_players_handler))
+        .or(warp::path!("admin" / "cells" / usize / usize)
+            .and(warp::get())
+            .and(with_admin(admin_keys.clone()))
+            .and(with_sender(sender.clone()))
+            .and_then(admin::get_cell_handler))
+        .or(warp::path!("admin" / "cells" / usize / usize)
+            .and(warp::put())
+            .and(with_admin(admin_keys.clone()))
+            .and(warp::body::json())
+            .and(with_sender(sender.clone()))
+            .and(with_audit_log(audit_log.clone()))
+            .and_then(admin::set_cell_handler))
+        .or(warp::path!("admin" / "players" / String / "teleport")
+            .and(warp::post())
+            .and(with_admin(admin_keys.clone()))
+            .and(warp::body::json())
+            .and(with_sender(sender.clone()))
+            .and(with_audit_log(audit_log.clone()))
+            .and_then(admin::teleport_handler))
+        .or(warp::path!("admin" / "players" / String / "kick")
+            .and(warp::post())
+            .and(with_admin(admin_keys.clone()))
+            .and(with_sender(sender.clone()))
+            .and(with_audit_log(audit_log.clone()))
+            .and_then(admin::kick_handler))
+        .or(warp::path!("admin" / "plots" / usize / "reset")
+            .and(warp::post())
+            .and(with_admin(admin_keys.clone()))
+            .and(with_sender(sender.clone()))
+            .and(with_audit_log(audit_log.clone()))
+            .and_then(admin::reset_plot_handler))
+        .or(warp::path!("admin" / "snapshot")
+            .and(warp::post())
+            .and(with_admin(admin_keys.clone()))
+            .and(with_sender(sender.clone()))
+            .and(with_audit_log(audit_log.clone()))
+            .and_then(admin::snapshot_handler));
+
+    let subscriptions = warp::path!("subscriptions" / String);
+    let subscription_routes = subscriptions
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_clients(clients.clone()))
+        .and_then(handler::subscribe_handler)
+        .or(subscriptions
+            .and(warp::delete())
+            .and(warp::body::json())
+            .and(with_clients(clients.clone()))
+            .and_then(handler::unsubscribe_handler));
+
     let ws_route = warp::path("ws")
         .and(warp::ws())
         .and(warp::path::param())
         .and(with_clients(clients.clone()))
         .and(with_sender(sender.clone()))
+        .and(with_metrics(metrics.clone()))
         .and_then(handler::ws_handler);
 
     let test_route = warp::path("test")
@@ -61,23 +169,115 @@ async fn main() {
     
     let static_assets = warp::path("static")
         .and(warp::get())
-        .and(warp::fs::dir("www/static"));
+        .and(warp::fs::dir(config.static_dir.clone()));
 
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_headers(vec!["User-Agent", "Sec-Fetch-Mode", "Referer", "Origin", "Content-Type", "Access-Control-Request-Method", "Access-Control-Request-Headers"])
-        .allow_methods(vec!["POST", "GET", "OPTIONS"]);
+    let cors = if config.cors_origins.is_empty() {
+        warp::cors().allow_any_origin()
+    } else {
+        warp::cors().allow_origins(config.cors_origins.iter().map(String::as_str))
+    };
+    let cors = cors
+        .allow_headers(vec!["Authorization", "User-Agent", "Sec-Fetch-Mode", "Referer", "Origin", "Content-Type", "Access-Control-Request-Method", "Access-Control-Request-Headers"])
+        .allow_methods(vec!["POST", "GET", "PUT", "DELETE", "OPTIONS"]);
 
+    let http_metrics = metrics.clone();
     let routes = health_route
+        .or(metrics_route)
         .or(register_routes)
         .or(ws_route)
         .or(publish)
+        .or(admin_routes)
+        .or(subscription_routes)
         .or(static_assets)
         .or(test_route)
-        .with(cors);
+        .recover(handler::handle_rejection)
+        .with(cors)
+        .with(warp::log::custom(move |info| {
+            http_metrics.http_requests
+                .with_label_values(&[info.method().as_str(), info.status().as_str()])
+                .inc();
+        }));
 
-    println!("Server started...");
-    warp::serve(routes).run(([127, 0, 0, 1], 8000)).await;
+    // Both servers stop accepting connections on a signal and return once
+    // in-flight requests are answered, so nobody registers after this
+    if config.tls.is_enabled() {
+        let acceptor = match tls::ReloadableAcceptor::new(config.tls.clone()) {
+            Ok(acceptor) => acceptor,
+            Err(e) => {
+                error!(error = %e, "could not load TLS certificate");
+                std::process::exit(2);
+            }
+        };
+        tokio::spawn(tls::reload_on_sighup(acceptor.clone()));
+        let listener = match TcpListener::bind(config.bind).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(bind = %config.bind, error = %e, "could not listen");
+                std::process::exit(1);
+            }
+        };
+        info!(bind = %config.bind, "server started with TLS");
+        warp::serve(routes)
+            .serve_incoming_with_graceful_shutdown(tls::incoming(listener, acceptor), shutdown_signal())
+            .await;
+    } else {
+        let (addr, server) = match warp::serve(routes).try_bind_with_graceful_shutdown(config.bind, shutdown_signal()) {
+            Ok(bound) => bound,
+            Err(e) => {
+                error!(bind = %config.bind, error = %e, "could not listen");
+                std::process::exit(1);
+            }
+        };
+        info!(bind = %addr, "server started");
+        server.await;
+    }
+
+    map::map_responder::shutdown(sender).await;
+    wait_for_disconnects(&clients).await;
+    info!("server stopped");
+}
+
+fn init_logging(config: &LogConfig) {
+    // The filter was checked when the config was loaded
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.filter))
+        .with_ansi(std::io::stdout().is_terminal());
+    match config.format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(true).init(),
+    }
+}
+
+// Resolves on Ctrl-C, or SIGTERM from a process manager
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!(error = %e, "could not listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    info!("shutting down");
+}
+
+// Gives clients a moment to finish closing their sockets
+async fn wait_for_disconnects(clients: &Clients) {
+    let started = Instant::now();
+    while !clients.read().await.is_empty() && started.elapsed() < DISCONNECT_TIMEOUT {
+        sleep(Duration::from_millis(50)).await;
+    }
 }
 
 
@@ -85,6 +285,31 @@ fn with_clients(clients: Clients) -> impl Filter<Extract = (Clients,), Error = I
     warp::any().map(move || clients.clone())
 }
 
+fn with_player_tokens(player_tokens: PlayerTokens) -> impl Filter<Extract = (PlayerTokens,), Error = Infallible> + Clone {
+    warp::any().map(move || player_tokens.clone())
+}
+
+fn with_config(config: Arc<Config>) -> impl Filter<Extract = (Arc<Config>,), Error = Infallible> + Clone {
+    warp::any().map(move || config.clone())
+}
+
+fn with_metrics(metrics: Arc<Metrics>) -> impl Filter<Extract = (Arc<Metrics>,), Error = Infallible> + Clone {
+    warp::any().map(move || metrics.clone())
+}
+
+fn with_audit_log(audit_log: Arc<AuditLog>) -> impl Filter<Extract = (Arc<AuditLog>,), Error = Infallible> + Clone {
+    warp::any().map(move || audit_log.clone())
+}
+
+// Passes on the name of the admin whose API key was presented
+fn with_admin(admin_keys: AdminKeys) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let admin = admin_keys.authenticate(authorization);
+            async move { admin }
+        })
+}
+
 fn with_sender(sender: map::MapSender) -> impl Filter<Extract = (map::MapSender,), Error = Infallible> + Clone {
     warp::any().map(move || sender.clone())
 }
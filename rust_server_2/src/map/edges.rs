@@ -0,0 +1,110 @@
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::map::{Coords, Dimensions, EdgeType, MapDirection};
+
+// Every edge of the map stored exactly once, so the two cells sharing a
+// border always agree on what is between them.
+//
+// `horizontal` holds the edge on the northern side of each cell, with an
+// extra row for the southern border of the map. `vertical` holds the edge on
+// the western side of each cell, with an extra column for the eastern border.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EdgeGrid {
+    dimensions: Dimensions,
+    horizontal: Vec<EdgeType>,
+    vertical: Vec<EdgeType>,
+}
+
+impl EdgeGrid {
+    pub fn new(dimensions: Dimensions) -> EdgeGrid {
+        EdgeGrid {
+            dimensions,
+            horizontal: vec![EdgeType::Passage; dimensions.width * (dimensions.height + 1)],
+            vertical: vec![EdgeType::Passage; (dimensions.width + 1) * dimensions.height],
+        }
+    }
+
+    pub fn dimensions(&self) -> Dimensions {
+        self.dimensions
+    }
+
+    pub fn get(&self, coords: &Coords, direction: &MapDirection) -> EdgeType {
+        match direction {
+            MapDirection::North | MapDirection::South => self.horizontal[self.horizontal_index(coords, direction)],
+            MapDirection::East | MapDirection::West => self.vertical[self.vertical_index(coords, direction)],
+        }
+    }
+
+    pub fn set(&mut self, coords: &Coords, direction: &MapDirection, edge_type: EdgeType) {
+        match direction {
+            MapDirection::North | MapDirection::South => {
+                let index = self.horizontal_index(coords, direction);
+                self.horizontal[index] = edge_type;
+            }
+            MapDirection::East | MapDirection::West => {
+                let index = self.vertical_index(coords, direction);
+                self.vertical[index] = edge_type;
+            }
+        }
+    }
+
+    pub fn cell_edges(&self, coords: &Coords) -> CellEdges {
+        CellEdges([
+            self.get(coords, &MapDirection::North),
+            self.get(coords, &MapDirection::East),
+            self.get(coords, &MapDirection::South),
+            self.get(coords, &MapDirection::West),
+        ])
+    }
+
+    fn horizontal_index(&self, coords: &Coords, direction: &MapDirection) -> usize {
+        let row = match direction {
+            MapDirection::South => coords.y + 1,
+            _ => coords.y,
+        };
+        row * self.dimensions.width + coords.x
+    }
+
+    fn vertical_index(&self, coords: &Coords, direction: &MapDirection) -> usize {
+        let column = match direction {
+            MapDirection::East => coords.x + 1,
+            _ => coords.x,
+        };
+        coords.y * (self.dimensions.width + 1) + column
+    }
+}
+
+// The four edges around a single cell, in North, East, South, West order.
+// Serializes as a map of the non-passage edges to stay compatible with the
+// `edges` object the web client reads, each edge named by a plain string.
+// One-way edges are `OneWayOut` if they can be crossed leaving the cell and
+// `OneWayIn` if only entering it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CellEdges([EdgeType; 4]);
+
+impl Serialize for CellEdges {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let count = self.0.iter().filter(|edge| **edge != EdgeType::Passage).count();
+        let mut map = serializer.serialize_map(Some(count))?;
+        for (direction, edge) in MapDirection::all().iter().zip(self.0.iter()) {
+            if *edge != EdgeType::Passage {
+                map.serialize_entry(direction, edge_name(edge, direction))?;
+            }
+        }
+        map.end()
+    }
+}
+
+fn edge_name(edge: &EdgeType, side: &MapDirection) -> &'static str {
+    match edge {
+        EdgeType::Passage => "Passage",
+        EdgeType::Wall => "Wall",
+        EdgeType::Door => "Door",
+        EdgeType::OpenDoor => "OpenDoor",
+        EdgeType::Gate => "Gate",
+        EdgeType::OpenGate => "OpenGate",
+        EdgeType::OneWay(allowed) if allowed == side => "OneWayOut",
+        EdgeType::OneWay(_) => "OneWayIn",
+    }
+}
@@ -1,4 +1,5 @@
 use crate::map::*;
+use tracing::debug;
 
 pub const PLOT_SIDE: usize = 20;
 
@@ -8,44 +9,104 @@ pub const MAP_SIDE: usize = PLOT_SIDE * 3;
 pub const HEIGHT: usize = MAP_SIDE;
 pub const WIDTH: usize = MAP_SIDE;
 pub const MAP_SIZE: usize = 9 * PLOT_SIZE;
+// Generated maps are a square grid of this many plots on each side
+pub const PLOTS_PER_SIDE: usize = 3;
 
 pub fn generate_map() -> Map {
+    generate_sized_map(PLOT_SIDE)
+}
+
+// Generates a 3x3 grid of walled plots, each `plot_side` cells across
+pub fn generate_sized_map(plot_side: usize) -> Map {
+    let map_side = plot_side * PLOTS_PER_SIDE;
+    let middle = PLOTS_PER_SIDE / 2;
+    let dimensions = Dimensions {
+        width: map_side,
+        height: map_side,
+    };
     let mut map = Map{
-        cells: Vec::with_capacity(MAP_SIZE),
+        cells: Vec::with_capacity(dimensions.size()),
+        edges: EdgeGrid::new(dimensions),
+        dimensions,
+        plots: Plots::new(plot_side, dimensions),
         player_state: HashMap::new(),
+        npcs: HashMap::new(),
+        next_entity_id: 0,
+        tick: 0,
+        schedule: Schedule::new(),
+        pathfinder: Pathfinder::new(),
+        pending_changes: TickChanges::default(),
     };
-    for index in 0..HEIGHT * WIDTH {
+    for index in 0..dimensions.size() {
         let cell: Cell = Cell::no_walls(index);
         map.cells.push(cell);
-        let y: usize = index / MAP_SIDE;
-        let x: usize = index - (MAP_SIDE * y);
-        if y % PLOT_SIDE == 0 { // Northern edge
-            create_wall(&mut map, &index, None, &MapDirection::North);
+        let coords = dimensions.coords(index);
+        if coords.y % plot_side == 0 { // Northern edge
+            map.edges.set(&coords, &MapDirection::North, EdgeType::Wall);
         }
-        if x % PLOT_SIDE == PLOT_SIDE - 1 { // Eastern edge
-            create_wall(&mut map, &index, None, &MapDirection::East);
+        if coords.x % plot_side == plot_side - 1 { // Eastern edge
+            map.edges.set(&coords, &MapDirection::East, EdgeType::Wall);
         }
-        if y % PLOT_SIDE == PLOT_SIDE - 1{ // Southern edge
-            create_wall(&mut map, &index, None, &MapDirection::South);
+        if coords.y % plot_side == plot_side - 1 { // Southern edge
+            map.edges.set(&coords, &MapDirection::South, EdgeType::Wall);
         }
-        if x % PLOT_SIDE == 0{ // Western edge
-            create_wall(&mut map, &index, None, &MapDirection::West);
+        if coords.x % plot_side == 0 { // Western edge
+            map.edges.set(&coords, &MapDirection::West, EdgeType::Wall);
         } 
     }
-    println!("Map cells {}", map.cells.len());
+
+    // A doorway in the middle of every wall shared by two plots. Doorways into
+    // the corner plots are gates, each with a one-way passage beside it so
+    // nobody is shut in when the gate is closed behind them.
+    for plot_y in 0..PLOTS_PER_SIDE {
+        for plot_x in 0..PLOTS_PER_SIDE {
+            if plot_x + 1 < PLOTS_PER_SIDE {
+                let coords = Coords {
+                    x: plot_x * plot_side + plot_side - 1,
+                    y: plot_y * plot_side + plot_side / 2,
+                };
+                if plot_y == middle {
+                    map.edges.set(&coords, &MapDirection::East, EdgeType::Door);
+                } else {
+                    map.edges.set(&coords, &MapDirection::East, EdgeType::Gate);
+                    let way_out = if plot_x == 0 { MapDirection::East } else { MapDirection::West };
+                    add_way_out(&mut map, plot_side, Coords { x: coords.x, y: coords.y + 1 }, MapDirection::East, way_out);
+                }
+            }
+            if plot_y + 1 < PLOTS_PER_SIDE {
+                let coords = Coords {
+                    x: plot_x * plot_side + plot_side / 2,
+                    y: plot_y * plot_side + plot_side - 1,
+                };
+                if plot_x == middle {
+                    map.edges.set(&coords, &MapDirection::South, EdgeType::Door);
+                } else {
+                    map.edges.set(&coords, &MapDirection::South, EdgeType::Gate);
+                    let way_out = if plot_y == 0 { MapDirection::South } else { MapDirection::North };
+                    add_way_out(&mut map, plot_side, Coords { x: coords.x + 1, y: coords.y }, MapDirection::South, way_out);
+                }
+            }
+        }
+    }
+
+    // A merchant by the spawn point and an animal in each corner plot
+    map.spawn_npc(NpcKind::Merchant, Coords { x: map_side / 2 + 2, y: map_side / 2 - 2 });
+    let last = PLOTS_PER_SIDE - 1;
+    for (plot_x, plot_y) in [(0, 0), (last, 0), (0, last), (last, last)] {
+        map.spawn_npc(NpcKind::Animal, Coords {
+            x: plot_x * plot_side + plot_side / 2,
+            y: plot_y * plot_side + plot_side / 2,
+        });
+    }
+    debug!(cells = map.cells.len(), "generated map");
     return map;
 }
 
-fn create_wall(map: &mut Map, cell_a: &usize, cell_b: Option<&usize>, direction: &MapDirection) {
-    map.cells[*cell_a]
-        .edges
-        .insert(direction.clone(), EdgeType::Wall);
-    match cell_b {
-        Some(cell) => {
-            map.cells[*cell]
-                .edges
-                .insert(direction.opposite(), EdgeType::Wall);
-        }
-        None => (),
-    };
+// Turns the wall on the given side of the cell into a passage that can only
+// be crossed heading `way_out`, if the plots are big enough to fit one beside
+// the gate
+fn add_way_out(map: &mut Map, plot_side: usize, coords: Coords, side: MapDirection, way_out: MapDirection) {
+    if plot_side >= 3 {
+        map.edges.set(&coords, &side, EdgeType::OneWay(way_out));
+    }
 }
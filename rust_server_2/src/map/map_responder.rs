@@ -1,10 +1,17 @@
 use serde::{Serialize, Deserialize};
 use serde_json::json;
 use tokio::time::sleep;
-use std::time::{Duration, Instant};
+use std::time::Instant;
+use crate::config::MapConfig;
+use crate::metrics::Metrics;
+use std::sync::Arc;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use crate::chat::{ChatDelivery, ChatError, ChatLimiter, ChatMessage, ChatRequest, CHAT_RADIUS};
 use crate::map::*;
+use crate::map::plots::PlotSaver;
 use crate::*;
 
+
 #[derive(Serialize)]
 pub struct MoveResponse {
     move_id: usize,
@@ -22,15 +29,43 @@ pub struct CellUpdateResponse{
 #[derive(Debug)]
 pub struct RegisterResponse {
     pub player_coords: Coords,
-    pub explored_cells: Vec<Cell>,
+    pub explored_cells: Vec<CellView>,
+    pub dimensions: Dimensions,
+    pub plots: Vec<plots::Plot>,
+    pub npcs: Vec<npcs::Npc>,
 }
 
 #[derive(Debug)]
 pub enum MapRequest{
     RegisterPlayer(String, tokio::sync::oneshot::Sender<RegisterResponse>),
     PlayerInput(PlayerInput),
+    PlayerCommand(PlayerCommand),
+    Chat(ChatMessage),
+    Admin(AdminRequest, tokio::sync::oneshot::Sender<AdminResponse>),
+    // Finish the current tick, save the world and let everyone know we're going
+    Shutdown(tokio::sync::oneshot::Sender<()>),
+}
+
+// Inspection and moderation of the world on behalf of the admin API
+#[derive(Debug)]
+pub enum AdminRequest {
+    ListPlayers,
+    GetCell(Coords),
+    SetCell(Coords, CellType),
+    Teleport(String, Coords),
+    Kick(String),
+    ResetPlot(usize),
+    Snapshot,
 }
 
+#[derive(Debug)]
+pub enum AdminError {
+    NotFound(String),
+    Failed(String),
+}
+
+pub type AdminResponse = std::result::Result<serde_json::Value, AdminError>;
+
 
 #[derive(Debug)]
 pub struct PlayerInput {
@@ -38,6 +73,33 @@ pub struct PlayerInput {
     pub input: Inputs,
 }
 
+#[derive(Debug)]
+pub struct PlayerCommand {
+    pub user_id: String,
+    pub command: Command,
+}
+
+// One-off actions, sent by the client as e.g. `{"type": "claim_plot"}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    ClaimPlot,
+    AbandonPlot,
+    InviteFriend { user_id: String },
+    RemoveFriend { user_id: String },
+    // Walk step by step to the given cell until interrupted by manual input
+    WalkTo { x: usize, y: usize },
+}
+
+// Anything a client can send over its websocket
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ClientMessage {
+    Command(Command),
+    Chat(ChatRequest),
+    Inputs(Inputs),
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Inputs {
     pub north: bool,
@@ -51,95 +113,154 @@ pub struct Inputs {
 // Communication happens exclusively, into and out of the loop, via channels
 pub async fn game_loop(
         mut map_receiver: tokio::sync::mpsc::Receiver<MapRequest>,
-        clients: Clients
+        clients: Clients,
+        config: MapConfig,
+        metrics: Arc<Metrics>,
     ) {
-    let mut map: Map = map_generator::generate_map();
+    let mut map: Map = map_generator::generate_sized_map(config.plot_side);
+    map.plots.load(&config.plots_path);
+    let plot_saver = PlotSaver::spawn(config.plots_path.clone());
+    if let Some(world) = snapshot::load(&config.snapshot_path) {
+        match map.restore(world) {
+            Ok(()) => info!(path = ?config.snapshot_path, "restored world"),
+            Err(e) => error!(path = ?config.snapshot_path, error = %e, "could not restore world"),
+        }
+    }
+    let mut chat_limiter = ChatLimiter::new();
 
     loop { 
         let frame_time = Instant::now();
-        let mut player_inputs:Vec<PlayerInput> = Vec::with_capacity(32);
-        let mut new_cells: Vec<Cell> = Vec::with_capacity(32);
-        while let Ok(request) = map_receiver.try_recv(){
-            match request {
-                // Special route for sending all cells to a connecting player
-                MapRequest::RegisterPlayer(user_id, resp_sender)=> {
-                    match map.player_state.get(&user_id) {
-                        Some(_) => (),
-                        None => {
-                            map.player_state.insert(
-                                user_id.clone(),
-                                Player{
-                                    user_id: user_id.clone(),
-                                    coords: Coords {
-                                        x: map_generator::MAP_SIDE / 2,
-                                        y: map_generator::MAP_SIDE / 2,
-                                    },
-                                    direction: MapDirection::North,
-                                    state: PlayerStates::Idle,
-                                    last_moved: Instant::now()
-                                }
-                            );
-                        }
+        // Everything logged while the tick runs carries its number
+        let tick_span = info_span!("tick", tick = map.ticks());
+        let stopped = async {
+            let mut player_inputs:Vec<PlayerInput> = Vec::with_capacity(32);
+            let mut player_commands: Vec<PlayerCommand> = Vec::new();
+            let mut chat_messages: Vec<ChatMessage> = Vec::new();
+            let mut shutdown = None;
+            let mut queued = 0;
+            while let Ok(request) = map_receiver.try_recv(){
+                queued += 1;
+                match request {
+                    // Special route for sending all cells to a connecting player
+                    MapRequest::RegisterPlayer(user_id, resp_sender)=> {
+                        let player_coords = map.register_player(&user_id).coords.clone();
+                        resp_sender.send(RegisterResponse{
+                            player_coords,
+                            explored_cells: map.cell_views(),
+                            dimensions: map.dimensions,
+                            plots: map.plots.all().to_vec(),
+                            npcs: map.npcs().into_iter().cloned().collect(),
+                        }).unwrap();
+                    },
+
+                    // Change the player's state based on a new input
+                    MapRequest::PlayerInput(player_input) => {
+                        player_inputs.push(player_input)
+                    }
+
+                    MapRequest::PlayerCommand(player_command) => {
+                        player_commands.push(player_command)
+                    }
+
+                    MapRequest::Chat(chat_message) => {
+                        chat_messages.push(chat_message)
+                    }
+
+                    MapRequest::Admin(admin_request, resp_sender) => {
+                        let response = respond_to_admin(&mut map, &clients, &config, admin_request).await;
+                        let _ = resp_sender.send(response);
+                    }
+
+                    MapRequest::Shutdown(resp_sender) => {
+                        shutdown = Some(resp_sender)
                     }
-                    resp_sender.send(RegisterResponse{
-                        player_coords: map.player_state[&user_id].coords.clone(),
-                        explored_cells: map.cells.clone()}
-                    ).unwrap();
-                },
-
-                // Change the player's state based on a new input
-                MapRequest::PlayerInput(player_input) => {
-                    player_inputs.push(player_input)
                 }
             }
-        }
 
-        // TODO: Create concept of entity id
-        let mut changed_player_ids: Vec<String> = Vec::with_capacity(32);
-        let mut changed_cell_indices: Vec<usize> = Vec::with_capacity(32);
+            let mut changes = map.tick(player_inputs, player_commands, frame_time);
         
-        // Update cells that change on their own
-        let cell_indices = map.update_cells();
-        changed_cell_indices.extend(cell_indices);
-
-        // Update anything the player acted on
-        let (player_ids, cell_indices) = map.update_player_state(player_inputs, frame_time);
-        changed_player_ids.extend(player_ids);
-        changed_cell_indices.extend(cell_indices);
+            changes.player_ids.sort_unstable();
+            changes.player_ids.dedup();
+            let new_player_states: Vec<&Player> = changes.player_ids.iter().filter_map(|user_id| map.player_state.get(user_id)).collect();
         
+            let new_npc_states: Vec<&npcs::Npc> = map.npcs().into_iter().filter(|npc| changes.npc_ids.contains(&npc.entity_id)).collect();
         
-        changed_player_ids.sort_unstable();
-        changed_player_ids.dedup();
-        let new_player_states: Vec<&Player> = changed_player_ids.iter().map(|user_id| map.player_state.get(user_id).unwrap()).collect();
-        
-        changed_cell_indices.sort_unstable();
-        changed_cell_indices.dedup();
-        let new_cells: Vec<&Cell> = changed_cell_indices.into_iter().map(|cell_index| &map.cells[cell_index]).collect();
+            changes.cell_indices.sort_unstable();
+            changes.cell_indices.dedup();
+            let new_cells: Vec<CellView> = changes.cell_indices.into_iter().map(|cell_index| map.cell_view(cell_index)).collect();
+
+            changes.edge_cell_indices.sort_unstable();
+            changes.edge_cell_indices.dedup();
+            let new_edges: Vec<EdgeView> = changes.edge_cell_indices.into_iter().map(|cell_index| map.edge_view(cell_index)).collect();
         
         
-        // Send changes to all players
-        for (_, client) in clients.read().await.iter(){
-            if let Some(sender) = &client.sender {
-                if new_player_states.len() != 0 {
-                    sender.send(Ok(Message::text(json!(
-                        {
-                            "type": "player_update",
-                            "players": &new_player_states
-                        }
-                    ).to_string()))).unwrap();
-                }
-                if new_cells.len() != 0 {
-                    sender.send(Ok(Message::text(json!(
-                        {
-                            "type": "cell_update",
-                            "cells": &new_cells
+            let updates = [
+                (topics::PLAYER_UPDATES, (!new_player_states.is_empty()).then(|| json!(
+                    {
+                        "type": "player_update",
+                        "players": &new_player_states
+                    }
+                ).to_string())),
+                (topics::NPC_UPDATES, (!new_npc_states.is_empty()).then(|| json!(
+                    {
+                        "type": "npc_update",
+                        "npcs": &new_npc_states
+                    }
+                ).to_string())),
+                (topics::CELL_UPDATES, (!new_cells.is_empty()).then(|| json!(
+                    {
+                        "type": "cell_update",
+                        "cells": &new_cells
+                    }
+                ).to_string())),
+                (topics::PLOT_UPDATES, changes.plots_changed.then(|| json!(
+                    {
+                        "type": "plot_update",
+                        "plots": map.plots.all()
+                    }
+                ).to_string())),
+                (topics::EDGE_UPDATES, (!new_edges.is_empty()).then(|| json!(
+                    {
+                        "type": "edge_update",
+                        "edges": &new_edges
+                    }
+                ).to_string())),
+            ];
+
+            let mut traffic = Traffic::default();
+            let clients_lock = clients.read().await;
+            for chat_message in chat_messages {
+                deliver_chat(&map, &clients_lock, &mut chat_limiter, &mut traffic, chat_message, frame_time);
+            }
+
+            // Send changes to all players
+            for (_, client) in clients_lock.iter(){
+                if let Some(sender) = &client.sender {
+                    for (topic, update) in &updates {
+                        if let Some(text) = update {
+                            if client.subscribed_to(topic) {
+                                traffic.send(sender, text);
+                            }
                         }
-                    ).to_string()))).unwrap();
+                    }
                 }
             }
+            drop(clients_lock);
+            metrics.queued_requests.set(queued);
+            traffic.record(&metrics);
+            metrics.tick_duration.observe(frame_time.elapsed().as_secs_f64());
+            if let Some(contents) = map.plots.take_unsaved() {
+                plot_saver.save(contents);
+            }
+            shutdown
+        }.instrument(tick_span).await;
+        if let Some(resp_sender) = stopped {
+            shut_down(&map, &clients, &config, plot_saver).await;
+            let _ = resp_sender.send(());
+            return;
         }
-        if frame_time.elapsed().as_millis() < 33 {
-            sleep(Duration::from_millis(33) - frame_time.elapsed()).await;
+        if let Some(remaining) = config.tick_duration().checked_sub(frame_time.elapsed()) {
+            sleep(remaining).await;
         }
     }
 }
@@ -157,7 +278,157 @@ pub async fn game_loop(
     // }
 
 
-pub async fn register_player<'a> (
+// Tallies what a tick sends so it can be reported in one go
+#[derive(Default)]
+struct Traffic {
+    messages: u64,
+    bytes: u64,
+}
+
+impl Traffic {
+    // Clients whose socket has gone away are skipped rather than counted
+    fn send(&mut self, sender: &ClientSender, text: &str) {
+        if sender.send(Ok(Message::text(text))).is_ok() {
+            self.messages += 1;
+            self.bytes += text.len() as u64;
+        }
+    }
+
+    fn record(&self, metrics: &Metrics) {
+        metrics.tick_messages.observe(self.messages as f64);
+        metrics.tick_bytes.observe(self.bytes as f64);
+        metrics.messages_sent.inc_by(self.messages);
+        metrics.bytes_sent.inc_by(self.bytes);
+    }
+}
+
+async fn shut_down(map: &Map, clients: &Clients, config: &MapConfig, plot_saver: PlotSaver) {
+    match snapshot::save(&map.snapshot(), &config.snapshot_path) {
+        Ok(()) => info!(path = ?config.snapshot_path, "saved world"),
+        Err(e) => error!(path = ?config.snapshot_path, error = %e, "could not save world"),
+    }
+    plot_saver.finish(map.plots.to_json()).await;
+
+    // Taking each sender closes the socket once the notice is flushed, and the
+    // client is removed when it finishes the close handshake
+    let notice = json!({"type": "shutdown"}).to_string();
+    clients.write().await.retain(|_, client| match client.sender.take() {
+        Some(sender) => {
+            let _ = sender.send(Ok(Message::text(notice.clone())));
+            true
+        }
+        None => false,
+    });
+}
+
+async fn respond_to_admin(map: &mut Map, clients: &Clients, config: &MapConfig, request: AdminRequest) -> AdminResponse {
+    match request {
+        AdminRequest::ListPlayers => Ok(json!(map.players())),
+        AdminRequest::GetCell(coords) => {
+            if !map.dimensions.contains(&coords) {
+                return Err(AdminError::NotFound(format!("no cell at {}", coords)));
+            }
+            Ok(json!(map.cell_view(map.dimensions.index(&coords))))
+        }
+        AdminRequest::SetCell(coords, cell_type) => {
+            if !map.set_cell_type(&coords, cell_type) {
+                return Err(AdminError::NotFound(format!("no cell at {}", coords)));
+            }
+            Ok(json!(map.cell_view(map.dimensions.index(&coords))))
+        }
+        AdminRequest::Teleport(user_id, coords) => {
+            if !map.teleport_player(&user_id, coords.clone()) {
+                return Err(AdminError::NotFound(format!("no player {} or no cell at {}", user_id, coords)));
+            }
+            Ok(json!({"user_id": user_id, "coords": coords}))
+        }
+        AdminRequest::Kick(user_id) => {
+            if !map.remove_player(&user_id) {
+                return Err(AdminError::NotFound(format!("no player {}", user_id)));
+            }
+            // Dropping a client's sender closes their socket once the notice is flushed
+            let notice = json!({"type": "kicked"}).to_string();
+            clients.write().await.retain(|_, client| {
+                if client.user_id != user_id {
+                    return true;
+                }
+                if let Some(sender) = &client.sender {
+                    let _ = sender.send(Ok(Message::text(notice.clone())));
+                }
+                false
+            });
+            Ok(json!({"user_id": user_id}))
+        }
+        AdminRequest::ResetPlot(index) => {
+            if !map.reset_plot(index) {
+                return Err(AdminError::NotFound(format!("no plot {}", index)));
+            }
+            Ok(json!(map.plots.all()[index]))
+        }
+        AdminRequest::Snapshot => match snapshot::save(&map.snapshot(), &config.snapshot_path) {
+            Ok(()) => Ok(json!({"path": config.snapshot_path})),
+            Err(e) => Err(AdminError::Failed(format!("error saving snapshot: {}", e))),
+        },
+    }
+}
+
+// Sends a chat message to everyone who should hear it, or tells the sender why it was refused
+fn deliver_chat(
+    map: &Map,
+    clients: &HashMap<String, Client>,
+    chat_limiter: &mut ChatLimiter,
+    traffic: &mut Traffic,
+    chat_message: ChatMessage,
+    now: Instant,
+) {
+    // Replies about the sender's own message skip the topic check
+    let mut send_to = |user_ids: &[String], topic: Option<&str>, text: String| {
+        for client in clients.values() {
+            if !user_ids.contains(&client.user_id) {
+                continue;
+            }
+            if topic.is_some_and(|topic| !client.subscribed_to(topic)) {
+                continue;
+            }
+            if let Some(sender) = &client.sender {
+                traffic.send(sender, &text);
+            }
+        }
+    };
+
+    let checked = chat_limiter.check(&chat_message, now).and_then(|()| match &chat_message.request {
+        ChatRequest::Whisper { user_id, .. } if !is_online(clients, user_id) => Err(ChatError::NotOnline),
+        _ => Ok(()),
+    });
+    if let Err(e) = checked {
+        send_to(std::slice::from_ref(&chat_message.from), None, json!(
+            {
+                "type": "chat_error",
+                "reason": e.reason()
+            }
+        ).to_string());
+        return;
+    }
+
+    let (recipients, text, whisper) = match &chat_message.request {
+        ChatRequest::Chat { text } => (map.nearby_player_ids(&chat_message.from, CHAT_RADIUS), text, false),
+        // Whispers are echoed back so the sender sees what they sent
+        ChatRequest::Whisper { user_id, text } => (vec![user_id.clone(), chat_message.from.clone()], text, true),
+    };
+    let delivery = ChatDelivery {
+        message_type: "chat",
+        from: &chat_message.from,
+        text,
+        whisper,
+    };
+    send_to(&recipients, Some(topics::CHAT), serde_json::to_string(&delivery).unwrap());
+}
+
+fn is_online(clients: &HashMap<String, Client>, user_id: &str) -> bool {
+    clients.values().any(|client| client.user_id == user_id && client.sender.is_some())
+}
+
+pub async fn register_player(
     map_sender: tokio::sync::mpsc::Sender<MapRequest>,
     user_id: String,
 ) -> RegisterResponse {
@@ -171,21 +442,119 @@ pub async fn register_player<'a> (
     return resp_receiver.await.unwrap();
 }
 
+pub async fn admin_request(
+    map_sender: MapSender,
+    request: AdminRequest,
+) -> AdminResponse {
+    // The game loop drops its end of both channels once it has shut down
+    let stopped = || AdminError::Failed("the game loop has stopped".to_string());
+    let (resp_sender, resp_receiver) = tokio::sync::oneshot::channel();
+    map_sender.send(MapRequest::Admin(request, resp_sender)).await.map_err(|_| stopped())?;
+    resp_receiver.await.map_err(|_| stopped())?
+}
+
+pub async fn shutdown(map_sender: MapSender) {
+    let (resp_sender, resp_receiver) = tokio::sync::oneshot::channel();
+    if map_sender.send(MapRequest::Shutdown(resp_sender)).await.is_ok() {
+        let _ = resp_receiver.await;
+    }
+}
+
 pub async fn respond_to_player(
     tx: &mut MapSender,
     user_id: String,
     message: &str,
 ){
-    println!("{:?}", message);
-    let input: Inputs =  match serde_json::from_str(&message){
+    let client_message: ClientMessage =  match serde_json::from_str(message){
         Ok(v) => v,
         Err(e) => {
-            eprintln!("error while parsing message to user input: {}", e);
+            warn!(error = %e, "could not parse client message");
             return;
         }
     };
-    println!("Received player input: {:?}", input);
-    let player_input = PlayerInput{user_id, input};
-    tx.send(MapRequest::PlayerInput(player_input)).await.unwrap();
+    let request = match client_message {
+        ClientMessage::Inputs(input) => {
+            debug!(?input, "player input");
+            MapRequest::PlayerInput(PlayerInput{user_id, input})
+        }
+        ClientMessage::Command(command) => {
+            debug!(?command, "player command");
+            MapRequest::PlayerCommand(PlayerCommand{user_id, command})
+        }
+        ClientMessage::Chat(request) => {
+            MapRequest::Chat(ChatMessage{from: user_id, request})
+        }
+    };
+    // The game loop stops taking requests once the server is shutting down
+    let _ = tx.send(request).await;
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    fn connect(clients: &mut HashMap<String, Client>, user_id: &str) -> UnboundedReceiver<std::result::Result<Message, warp::Error>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        clients.insert(user_id.to_string(), Client {
+            user_id: user_id.to_string(),
+            topics: topics::default_subscriptions(),
+            sender: Some(sender),
+        });
+        receiver
+    }
+
+    fn received(receiver: &mut UnboundedReceiver<std::result::Result<Message, warp::Error>>) -> Vec<serde_json::Value> {
+        let mut messages = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            messages.push(serde_json::from_str(message.unwrap().to_str().unwrap()).unwrap());
+        }
+        messages
+    }
+
+    fn whisper(from: &str, to: &str) -> ChatMessage {
+        ChatMessage {
+            from: from.to_string(),
+            request: ChatRequest::Whisper { user_id: to.to_string(), text: "psst".to_string() },
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_requests_fail_once_the_game_loop_has_stopped() {
+        let (map_sender, map_receiver) = mpsc::channel(1);
+        drop(map_receiver);
+        let response = admin_request(map_sender, AdminRequest::ListPlayers).await;
+        assert!(matches!(response, Err(AdminError::Failed(_))));
+    }
+
+    #[test]
+    fn whispering_to_someone_offline_is_refused() {
+        let map = map_generator::generate_sized_map(5);
+        let mut clients = HashMap::new();
+        let mut ann = connect(&mut clients, "ann");
+
+        deliver_chat(&map, &clients, &mut ChatLimiter::new(), &mut Traffic::default(), whisper("ann", "bob"), Instant::now());
+
+        let messages = received(&mut ann);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["type"], "chat_error");
+        assert_eq!(messages[0]["reason"], ChatError::NotOnline.reason());
+    }
+
+    #[test]
+    fn whispers_reach_the_recipient_and_echo_to_the_sender() {
+        let map = map_generator::generate_sized_map(5);
+        let mut clients = HashMap::new();
+        let mut ann = connect(&mut clients, "ann");
+        let mut bob = connect(&mut clients, "bob");
+
+        deliver_chat(&map, &clients, &mut ChatLimiter::new(), &mut Traffic::default(), whisper("ann", "bob"), Instant::now());
+
+        for messages in [received(&mut ann), received(&mut bob)] {
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0]["type"], "chat");
+            assert_eq!(messages[0]["whisper"], true);
+        }
+    }
+}
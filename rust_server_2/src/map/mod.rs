@@ -1,88 +1,467 @@
-use std::collections::HashMap;
-use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::hash::Hash;
 use std::time::Instant;
 use std::time::Duration;
 
 use self::map_responder::PlayerInput;
-use self::map_responder::Inputs;
+use self::map_responder::PlayerCommand;
+use self::map_responder::{Command, Inputs};
+use self::schedule::Schedule;
+use self::edges::{CellEdges, EdgeGrid};
+use self::plots::Plots;
+use self::npcs::{Npc, NpcKind};
+use self::pathfinding::Pathfinder;
 
 pub mod map_responder;
-mod map_generator;
+pub mod map_generator;
+pub mod plots;
+pub mod npcs;
+pub mod snapshot;
+mod schedule;
+mod edges;
+mod pathfinding;
+
+// Number of ticks a plant takes to grow into a flower
+const GROWTH_TICKS: u64 = 50;
+
+// Identifies anything that moves around the map, players and NPCs alike
+pub type EntityId = u64;
+
+// Everything that changed during a tick and needs to be sent to clients
+#[derive(Debug, Default)]
+pub struct TickChanges {
+    pub player_ids: Vec<String>,
+    pub npc_ids: Vec<EntityId>,
+    pub cell_indices: Vec<usize>,
+    // Cells on either side of an edge that changed
+    pub edge_cell_indices: Vec<usize>,
+    pub plots_changed: bool,
+}
+
+// A change a player asked to make to the map
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MapChange {
+    Cell(usize, CellType),
+    Edge(Coords, MapDirection, EdgeType),
+}
 
 pub struct Map {
     pub cells: Vec<Cell>,
+    pub edges: EdgeGrid,
+    pub dimensions: Dimensions,
+    pub plots: Plots,
     player_state: HashMap<String, Player>,
+    npcs: HashMap<EntityId, Npc>,
+    next_entity_id: EntityId,
+    tick: u64,
+    schedule: Schedule,
+    pathfinder: Pathfinder,
+    // Changes made between ticks, e.g. by admins, waiting to be sent out
+    pending_changes: TickChanges,
 }
 
 impl Map {
-    fn update_player_state(&mut self, inputs: Vec<PlayerInput>, frame_time: Instant) -> (Vec<String>, Vec<usize>){
+    // Advance the world by one frame
+    pub fn tick(&mut self, inputs: Vec<PlayerInput>, commands: Vec<PlayerCommand>, frame_time: Instant) -> TickChanges {
+        let mut changes = std::mem::take(&mut self.pending_changes);
+
+        for command in commands {
+            self.apply_command(command, &mut changes);
+        }
+
+        // Update cells that change on their own
+        changes.cell_indices.extend(self.update_cells());
+
+        // Update anything the player acted on
+        self.update_player_state(inputs, frame_time, &mut changes);
+
+        self.update_npcs(frame_time, &mut changes);
+
+        changes
+    }
+
+    // Adds a player at the centre of the map unless they are already on it
+    pub fn register_player(&mut self, user_id: &str) -> &Player {
+        if !self.player_state.contains_key(user_id) {
+            let entity_id = self.new_entity_id();
+            let spawn = Coords {
+                x: self.dimensions.width / 2,
+                y: self.dimensions.height / 2,
+            };
+            self.player_state.insert(
+                user_id.to_string(),
+                Player::new(entity_id, user_id.to_string(), spawn)
+            );
+        }
+        &self.player_state[user_id]
+    }
+
+    pub fn spawn_npc(&mut self, kind: NpcKind, coords: Coords) -> EntityId {
+        let entity_id = self.new_entity_id();
+        self.npcs.insert(entity_id, Npc::new(entity_id, kind, coords));
+        entity_id
+    }
+
+    // Players within `radius` cells of the given player or in the same plot,
+    // including the player themselves
+    pub fn nearby_player_ids(&self, user_id: &str, radius: usize) -> Vec<String> {
+        let coords = match self.player_state.get(user_id) {
+            Some(player) => &player.coords,
+            None => return Vec::new(),
+        };
+        let plot = self.plots.index_at(coords);
+        self.player_state
+            .values()
+            .filter(|other| {
+                other.coords.x.abs_diff(coords.x) + other.coords.y.abs_diff(coords.y) <= radius
+                    || self.plots.index_at(&other.coords) == plot
+            })
+            .map(|other| other.user_id.clone())
+            .collect()
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn players(&self) -> Vec<&Player> {
+        self.player_state.values().collect()
+    }
+
+    // Also forgets any change waiting to be sent out for the player
+    pub fn remove_player(&mut self, user_id: &str) -> bool {
+        self.pending_changes.player_ids.retain(|pending| pending != user_id);
+        self.player_state.remove(user_id).is_some()
+    }
+
+    pub fn teleport_player(&mut self, user_id: &str, coords: Coords) -> bool {
+        if !self.dimensions.contains(&coords) {
+            return false;
+        }
+        match self.player_state.get_mut(user_id) {
+            Some(player) => {
+                player.coords = coords;
+                player.route.clear();
+                player.state = PlayerStates::Idle;
+                self.pending_changes.player_ids.push(user_id.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Changes a cell outside of the usual player inputs, sending it out next tick
+    pub fn set_cell_type(&mut self, coords: &Coords, cell_type: CellType) -> bool {
+        if !self.dimensions.contains(coords) {
+            return false;
+        }
+        let index = self.dimensions.index(coords);
+        self.change_cell_type(index, cell_type);
+        self.pending_changes.cell_indices.push(index);
+        true
+    }
+
+    // Returns a plot to nobody and turns all of its cells back into soil
+    pub fn reset_plot(&mut self, plot_index: usize) -> bool {
+        let (top_left, bottom_right) = match self.plots.bounds(plot_index) {
+            Some(bounds) => bounds,
+            None => return false,
+        };
+        self.plots.reset(plot_index);
+        self.pending_changes.plots_changed = true;
+        for y in top_left.y..=bottom_right.y {
+            for x in top_left.x..=bottom_right.x {
+                let index = self.dimensions.index(&Coords { x, y });
+                if self.cells[index].cell_type != CellType::Soil {
+                    self.change_cell_type(index, CellType::Soil);
+                    self.pending_changes.cell_indices.push(index);
+                }
+            }
+        }
+        true
+    }
+
+    pub fn npcs(&self) -> Vec<&Npc> {
+        self.npcs.values().collect()
+    }
+
+    fn new_entity_id(&mut self) -> EntityId {
+        self.next_entity_id += 1;
+        self.next_entity_id
+    }
+
+    pub fn change_cell_type(&mut self, index: usize, cell_type: CellType) {
+        if cell_type == CellType::Plant {
+            self.schedule.schedule(self.tick + GROWTH_TICKS, index);
+        }
+        self.cells[index].change_type(cell_type, self.tick);
+    }
+
+    // Returns the indices of the cells on either side of the edge
+    pub fn change_edge(&mut self, coords: &Coords, direction: &MapDirection, edge_type: EdgeType) -> Vec<usize> {
+        self.edges.set(coords, direction, edge_type);
+        self.pathfinder.invalidate();
+        let mut indices = vec![self.dimensions.index(coords)];
+        if let Some(neighbour) = step_in_direction(coords, direction, self.dimensions) {
+            indices.push(self.dimensions.index(&neighbour));
+        }
+        indices
+    }
+
+    // Shortest route between two cells given the current state of the edges
+    pub fn find_path(&mut self, from: &Coords, to: &Coords) -> Option<Vec<MapDirection>> {
+        if !self.dimensions.contains(from) || !self.dimensions.contains(to) {
+            return None;
+        }
+        self.pathfinder.path(&self.edges, from, to)
+    }
+
+    pub fn is_reachable(&mut self, from: &Coords, to: &Coords) -> bool {
+        self.find_path(from, to).is_some()
+    }
+
+    pub fn cell_view(&self, index: usize) -> CellView {
+        let cell = &self.cells[index];
+        CellView {
+            index,
+            cell_type: cell.cell_type.clone(),
+            edges: self.edges.cell_edges(&self.dimensions.coords(index)),
+            lifetime: cell.lifetime(self.tick),
+        }
+    }
+
+    pub fn edge_view(&self, index: usize) -> EdgeView {
+        EdgeView {
+            index,
+            edges: self.edges.cell_edges(&self.dimensions.coords(index)),
+        }
+    }
+
+    pub fn cell_views(&self) -> Vec<CellView> {
+        (0..self.cells.len()).map(|index| self.cell_view(index)).collect()
+    }
+
+    // Number of cell changes waiting on the schedule, including stale ones
+    pub fn pending_cell_updates(&self) -> usize {
+        self.schedule.len()
+    }
+
+    fn apply_command(&mut self, player_command: PlayerCommand, changes: &mut TickChanges) {
+        let user_id = player_command.user_id;
+        let player = match self.player_state.get(&user_id) {
+            Some(player) => player,
+            None => return,
+        };
+        let coords = player.coords.clone();
+        let changed = match player_command.command {
+            // Claim the plot the player is standing in
+            Command::ClaimPlot => self.plots.claim(&user_id, &coords),
+            Command::AbandonPlot => self.plots.abandon(&user_id),
+            Command::InviteFriend { user_id: friend_id } => self.plots.invite(&user_id, &friend_id),
+            Command::RemoveFriend { user_id: friend_id } => self.plots.uninvite(&user_id, &friend_id),
+            Command::WalkTo { x, y } => {
+                let route = self.find_path(&coords, &Coords { x, y });
+                if let Some(route) = route {
+                    self.player_state.get_mut(&user_id).unwrap().walk(route);
+                }
+                false
+            }
+        };
+        changes.plots_changed |= changed;
+    }
+
+    fn update_player_state(&mut self, inputs: Vec<PlayerInput>, frame_time: Instant, changes: &mut TickChanges) {
         // Apply all player commands
-        let mut changed_cell_indices: Vec<usize> = Vec::with_capacity(32);
-        for input in inputs {
-            let player : &mut Player = self.player_state.get_mut(&input.user_id).unwrap();
-            let changed_cell_index = player.apply_inputs(&mut self.cells, input.input, frame_time);
-            if let Some(index) = changed_cell_index {changed_cell_indices.push(index)};
+        for mut input in inputs {
+            // Inputs can still be queued from a player kicked earlier in the tick
+            let Some(player) = self.player_state.get_mut(&input.user_id) else { continue };
+
+            // Interacting with a merchant trades with them instead of touching the cell they stand on
+            if input.input.interact {
+                if let Some(facing) = adjust_in_direction(&player.coords, &player.direction, &self.edges) {
+                    if self.npcs.values().any(|npc| npc.kind == NpcKind::Merchant && npc.coords == facing) {
+                        player.trade_flower_for_key();
+                        input.input.interact = false;
+                    }
+                }
+            }
+
+            let map_change = player.apply_inputs(&self.cells, &self.edges, &self.plots, input.input, frame_time);
+            match map_change {
+                Some(MapChange::Cell(index, cell_type)) => {
+                    self.change_cell_type(index, cell_type);
+                    changes.cell_indices.push(index);
+                }
+                Some(MapChange::Edge(coords, direction, edge_type)) => {
+                    let indices = self.change_edge(&coords, &direction, edge_type);
+                    changes.edge_cell_indices.extend(indices);
+                }
+                None => (),
+            };
         }
         
         // Apply existing state e.g. if the player is already in motion
-        for (_, player) in &mut self.player_state{
-            player.update(&self.cells, frame_time);
+        for player in self.player_state.values_mut() {
+            player.update(&self.edges, frame_time);
         };
 
-        return (
-            // TODO: Actually only return changed
-            self.player_state.iter().map(|(user_id, _)| user_id.clone()).collect()
-            , changed_cell_indices
-        )
+        // TODO: Actually only return changed
+        changes.player_ids.extend(self.player_state.keys().cloned());
+    }
+
+    fn update_npcs(&mut self, frame_time: Instant, changes: &mut TickChanges) {
+        let mut eaten_cell_indices: Vec<usize> = Vec::new();
+        for npc in self.npcs.values_mut() {
+            if npc.update(&self.cells, &self.edges, frame_time) {
+                changes.npc_ids.push(npc.entity_id);
+            }
+            let index = self.dimensions.index(&npc.coords);
+            if npc.eats(&self.cells[index]) {
+                eaten_cell_indices.push(index);
+            }
+        }
+        for index in eaten_cell_indices {
+            self.change_cell_type(index, CellType::Soil);
+            changes.cell_indices.push(index);
+        }
     }
 
     fn update_cells(&mut self) -> Vec<usize>{ 
+        self.tick += 1;
         let mut new_cell_indices: Vec<usize> = Vec::with_capacity(32);
-        for cell in &mut self.cells{
-            let changed = cell.update();
-            if changed {new_cell_indices.push(cell.index.clone())};
+        for index in self.schedule.pop_due(self.tick) {
+            let cell = &mut self.cells[index];
+            // Skip events left behind by a cell that has changed since it was scheduled
+            if cell.cell_type == CellType::Plant && cell.changed_at + GROWTH_TICKS <= self.tick {
+                cell.change_type(CellType::Flower, self.tick);
+                new_cell_indices.push(index);
+            }
         }
         return new_cell_indices;
     }
 }
 
-#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Dimensions {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Dimensions {
+    pub fn size(&self) -> usize {
+        self.width * self.height
+    }
+
+    pub fn contains(&self, coords: &Coords) -> bool {
+        coords.x < self.width && coords.y < self.height
+    }
+
+    pub fn index(&self, coords: &Coords) -> usize {
+        coords.y * self.width + coords.x
+    }
+
+    pub fn coords(&self, index: usize) -> Coords {
+        Coords {
+            x: index % self.width,
+            y: index / self.width,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum Item {
+    Key,
+    Flower,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct Player{
+    entity_id: EntityId,
     user_id: String,
     coords: Coords,
     direction: MapDirection,
-    #[serde(skip_serializing)]
+    inventory: Vec<Item>,
+    #[serde(skip, default)]
     state: PlayerStates,
-    #[serde(skip_serializing)]
+    #[serde(skip, default = "Instant::now")]
     last_moved: Instant,
+    // Steps left to take towards a destination the player asked to walk to
+    #[serde(skip)]
+    route: VecDeque<MapDirection>,
 }
 
 impl Player {
-    fn update(&mut self, cells: &Vec<Cell>, frame_time: Instant) {
-        let move_interval = Duration::new(0, 100000000);
-        let mut direction_to_move: Option<MapDirection> = None;
-        match self.state {
-            PlayerStates::MovingNorth => { direction_to_move = Some(MapDirection::North) },
-            PlayerStates::MovingEast => { direction_to_move = Some(MapDirection::East) },
-            PlayerStates::MovingWest => { direction_to_move = Some(MapDirection::West) },
-            PlayerStates::MovingSouth => { direction_to_move = Some(MapDirection::South) },
-            _ => (),
+    fn new(entity_id: EntityId, user_id: String, coords: Coords) -> Player {
+        Player {
+            entity_id,
+            user_id,
+            coords,
+            direction: MapDirection::North,
+            inventory: Vec::new(),
+            state: PlayerStates::Idle,
+            last_moved: Instant::now(),
+            route: VecDeque::new(),
+        }
+    }
+
+    fn walk(&mut self, route: Vec<MapDirection>) {
+        self.route = route.into_iter().collect();
+        // Already there, so there is nothing to walk
+        self.state = if self.route.is_empty() { PlayerStates::Idle } else { PlayerStates::Walking };
+    }
+
+    fn has_item(&self, item: &Item) -> bool {
+        self.inventory.contains(item)
+    }
+
+    fn trade_flower_for_key(&mut self) {
+        if let Some(position) = self.inventory.iter().position(|item| *item == Item::Flower) {
+            self.inventory[position] = Item::Key;
         }
+    }
+
+    fn update(&mut self, edges: &EdgeGrid, frame_time: Instant) {
+        let move_interval = Duration::new(0, 100000000);
+        let direction_to_move = match self.state {
+            PlayerStates::MovingNorth => Some(MapDirection::North),
+            PlayerStates::MovingEast => Some(MapDirection::East),
+            PlayerStates::MovingWest => Some(MapDirection::West),
+            PlayerStates::MovingSouth => Some(MapDirection::South),
+            PlayerStates::Walking => self.route.front().copied(),
+            _ => None,
+        };
         if self.last_moved + move_interval <= frame_time {
             if let Some(direction_to_move) = direction_to_move {
-                let new_coords: Option<Coords> = self.move_in_direction(cells, direction_to_move);
+                let new_coords: Option<Coords> = self.move_in_direction(edges, direction_to_move);
+                let blocked = new_coords.is_none();
                 if let Some(new_coords) = new_coords { self.coords = new_coords };
-                self.last_moved = frame_time.clone();
+                self.last_moved = frame_time;
+
+                if self.state == PlayerStates::Walking {
+                    self.direction = direction_to_move;
+                    self.route.pop_front();
+                    // Stop once we arrive, or if something now blocks the route
+                    if self.route.is_empty() || blocked {
+                        self.route.clear();
+                        self.state = PlayerStates::Idle;
+                    }
+                }
             }
         }
     }
 
-    fn apply_inputs(&mut self, cells: &mut Vec<Cell>, inputs: Inputs, frame_time: Instant) -> Option<usize>{
+    fn apply_inputs(&mut self, cells: &[Cell], edges: &EdgeGrid, plots: &Plots, inputs: Inputs, frame_time: Instant) -> Option<MapChange>{
         // Can move once every 100ms (aka 10 times per sec)
         let move_interval = Duration::new(0, 100000000);
 
+        // Any manual input interrupts walking to a destination
+        if self.state == PlayerStates::Walking {
+            self.route.clear();
+            self.state = PlayerStates::Idle;
+        }
+
         // CHeck if we stopped moving
         if !inputs.north && self.state == PlayerStates::MovingNorth {self.state = PlayerStates::Idle};
         if !inputs.east && self.state == PlayerStates::MovingEast {self.state = PlayerStates::Idle};
@@ -94,7 +473,7 @@ impl Player {
         // without first releasing all input keys
         if inputs.north {
             if self.state == PlayerStates::Idle && self.direction == MapDirection::North && self.last_moved + move_interval <= frame_time {
-                let new_coords: Option<Coords> = self.move_in_direction(cells, MapDirection::North);
+                let new_coords: Option<Coords> = self.move_in_direction(edges, MapDirection::North);
                 if let Some(new_coords) = new_coords { self.coords = new_coords };
                 self.last_moved = frame_time;
                 self.state = PlayerStates::MovingNorth 
@@ -106,7 +485,7 @@ impl Player {
         }
         else if inputs.east {
             if self.state == PlayerStates::Idle && self.direction == MapDirection::East && self.last_moved + move_interval <= frame_time {
-                let new_coords: Option<Coords> = self.move_in_direction(cells, MapDirection::East);
+                let new_coords: Option<Coords> = self.move_in_direction(edges, MapDirection::East);
                 if let Some(new_coords) = new_coords { self.coords = new_coords };
                 self.last_moved = frame_time;
                 self.state = PlayerStates::MovingEast 
@@ -118,7 +497,7 @@ impl Player {
         }
         else if inputs.south {
             if self.state == PlayerStates::Idle && self.direction == MapDirection::South && self.last_moved + move_interval <= frame_time {
-                let new_coords: Option<Coords> = self.move_in_direction(cells, MapDirection::South);
+                let new_coords: Option<Coords> = self.move_in_direction(edges, MapDirection::South);
                 if let Some(new_coords) = new_coords { self.coords = new_coords };
                 self.last_moved = frame_time;
                 self.state = PlayerStates::MovingSouth 
@@ -130,7 +509,7 @@ impl Player {
         } 
         else if inputs.west {
             if self.state == PlayerStates::Idle && self.direction == MapDirection::West && self.last_moved + move_interval <= frame_time {
-                let new_coords: Option<Coords> = self.move_in_direction(cells, MapDirection::West);
+                let new_coords: Option<Coords> = self.move_in_direction(edges, MapDirection::West);
                 if let Some(new_coords) = new_coords { self.coords = new_coords };
                 self.last_moved = frame_time;
                 self.state = PlayerStates::MovingWest 
@@ -142,17 +521,24 @@ impl Player {
         }
 
         if inputs.interact{
-            let facing_cell_coords = adjust_in_direction(&self.coords, &self.direction, &cells);
+            // Doors and gates in front of the player take priority over the cell behind them
+            let facing_edge = edges.get(&self.coords, &self.direction);
+            if let Some(edge_type) = self.interact_with_edge(facing_edge) {
+                return Some(MapChange::Edge(self.coords.clone(), self.direction, edge_type));
+            }
+
+            let facing_cell_coords = adjust_in_direction(&self.coords, &self.direction, edges);
             if let Some(cell_coords) = facing_cell_coords {
-                let index = get_index_from_coords(&cell_coords);
+                // Only the owner of a plot and their friends may farm it
+                if !plots.can_farm(&self.user_id, &cell_coords) {
+                    return None;
+                }
+                let index = edges.dimensions().index(&cell_coords);
                 match cells[index].cell_type {
-                    CellType::Soil => {
-                        cells[index].change_type(CellType::Plant);
-                        return Some(index);
-                    }
+                    CellType::Soil => return Some(MapChange::Cell(index, CellType::Plant)),
                     CellType::Flower => {
-                        cells[index].change_type(CellType::Soil);
-                        return Some(index);
+                        self.inventory.push(Item::Flower);
+                        return Some(MapChange::Cell(index, CellType::Soil))
                     }
                     _ => return None
                 }
@@ -162,79 +548,91 @@ impl Player {
         return None
     }
 
-    fn move_in_direction(&self, cells: &Vec<Cell>, direction: MapDirection) -> Option<Coords> {
-        let new_position = adjust_in_direction(&self.coords, &direction, cells);
-        if let Some(new_position) = new_position {
-            let next_cell = cells.get(get_index_from_coords(&new_position)).clone();
-            if let Some(_) = next_cell { 
-                return Some(new_position)
-            }
+    fn interact_with_edge(&self, edge_type: EdgeType) -> Option<EdgeType> {
+        match edge_type {
+            EdgeType::Door => Some(EdgeType::OpenDoor),
+            EdgeType::OpenDoor => Some(EdgeType::Door),
+            EdgeType::Gate if self.has_item(&Item::Key) => Some(EdgeType::OpenGate),
+            EdgeType::OpenGate if self.has_item(&Item::Key) => Some(EdgeType::Gate),
+            _ => None,
         }
-        return None
+    }
+
+    fn move_in_direction(&self, edges: &EdgeGrid, direction: MapDirection) -> Option<Coords> {
+        adjust_in_direction(&self.coords, &direction, edges)
     }
 }
 
-#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+#[derive(Serialize, Debug, Clone, Default, Eq, PartialEq)]
 pub enum PlayerStates {
+    #[default]
     Idle,
     Looking,
     MovingNorth,
     MovingEast,
     MovingSouth,
-    MovingWest
+    MovingWest,
+    // Following a route to a destination
+    Walking,
 }
 
 pub type MapSender = tokio::sync::mpsc::Sender<map_responder::MapRequest>;
 
-#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
-enum CellType {
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum CellType {
     Soil,
     Plant,
     Flower,
 }
 
-#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
 pub struct Cell {
     index: usize,
     cell_type: CellType,
-    edges: HashMap<MapDirection, EdgeType>,
-    lifetime: u64,
+    // Tick at which the cell last changed type
+    changed_at: u64,
 }
 
 impl Cell {
     fn no_walls(index: usize) -> Cell {
         return Cell {
-            index: index,
+            index,
             cell_type: CellType::Soil,
-            edges: HashMap::new(),
-            lifetime: 0,
+            changed_at: 0,
         };
     }
 
-    fn change_type(&mut self, cell_type: CellType){
-        self.lifetime = 0;
+    fn change_type(&mut self, cell_type: CellType, tick: u64){
+        self.changed_at = tick;
         self.cell_type = cell_type;
     }
-    
-    fn clone_with_new_lifetime(&self, lifetime: u64) -> Cell{
-        let mut new_cell = self.clone();
-        new_cell.lifetime = lifetime;
-        return new_cell;
-    }
 
-    fn update(&mut self) -> bool {
+    // Ticks the cell has spent growing as a plant, zero for any other type
+    fn lifetime(&self, tick: u64) -> u64 {
         if self.cell_type == CellType::Plant {
-            self.lifetime += 1;
-            if self.lifetime >= 50 {
-                self.change_type(CellType::Flower);
-                return true;
-            }
+            return tick - self.changed_at;
         }
-        return false;
+        return 0;
     }
 }
 
-#[derive(Serialize, Clone, Debug, Hash, Eq, PartialEq)]
+// A cell together with the edges around it, as sent to clients
+#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct CellView {
+    index: usize,
+    cell_type: CellType,
+    edges: CellEdges,
+    lifetime: u64,
+}
+
+// The edges around a cell, as sent to clients when only the edges changed
+#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct EdgeView {
+    index: usize,
+    edges: CellEdges,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
 pub struct Coords {
     pub x: usize,
     pub y: usize,
@@ -252,7 +650,7 @@ pub struct Room {
     color: String,
 }
 
-#[derive(Clone, Eq, PartialEq, Serialize, Hash, Debug)]
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Hash, Debug)]
 pub enum MapDirection {
     North,
     East,
@@ -261,8 +659,8 @@ pub enum MapDirection {
 }
 
 impl MapDirection {
-    fn all() -> Vec<MapDirection> {
-        return vec![
+    fn all() -> [MapDirection; 4] {
+        return [
             MapDirection::North,
             MapDirection::East,
             MapDirection::South,
@@ -280,30 +678,50 @@ impl MapDirection {
     }
 }
 
-#[derive(Serialize, Clone, Debug, PartialEq, Eq, Hash)]
-enum EdgeType {
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EdgeType {
     Passage,
     Wall,
+    // Opened and closed by interacting with it
+    Door,
+    OpenDoor,
+    // A door that needs a key to open or close
+    Gate,
+    OpenGate,
+    // Can only be crossed when moving in the given direction
+    OneWay(MapDirection),
 }
 
-fn get_index_from_coords(coords: &Coords) -> usize {
-    return coords.y * map_generator::MAP_SIDE + coords.x
+impl EdgeType {
+    fn allows(&self, direction: &MapDirection) -> bool {
+        match self {
+            EdgeType::Passage | EdgeType::OpenDoor | EdgeType::OpenGate => true,
+            EdgeType::Wall | EdgeType::Door | EdgeType::Gate => false,
+            EdgeType::OneWay(allowed) => allowed == direction,
+        }
+    }
 }
 
 pub fn adjust_in_direction(
     active_coord: &Coords,
     direction: &MapDirection,
-    cells: &Vec<Cell>,
+    edges: &EdgeGrid,
+) -> Option<Coords> {
+    if !edges.get(active_coord, direction).allows(direction) {
+        return None;
+    }
+    step_in_direction(active_coord, direction, edges.dimensions())
+}
+
+// The neighbouring coords in the given direction, ignoring edges
+fn step_in_direction(
+    active_coord: &Coords,
+    direction: &MapDirection,
+    dimensions: Dimensions,
 ) -> Option<Coords> {
-    let edges = &cells[get_index_from_coords(&active_coord)].edges;
     match direction {
         MapDirection::North => {
-            if active_coord.y == 0
-                || edges
-                    .get(&MapDirection::North)
-                    .unwrap_or(&EdgeType::Passage)
-                    == &EdgeType::Wall
-            {
+            if active_coord.y == 0 {
                 return None;
             };
             return Some(Coords {
@@ -312,9 +730,7 @@ pub fn adjust_in_direction(
             });
         }
         MapDirection::East => {
-            if active_coord.x == (map_generator::WIDTH - 1)
-                || edges.get(&MapDirection::East).unwrap_or(&EdgeType::Passage) == &EdgeType::Wall
-            {
+            if active_coord.x == (dimensions.width - 1) {
                 return None;
             };
             return Some(Coords {
@@ -323,12 +739,7 @@ pub fn adjust_in_direction(
             });
         }
         MapDirection::South => {
-            if active_coord.y == (map_generator::HEIGHT - 1)
-                || edges
-                    .get(&MapDirection::South)
-                    .unwrap_or(&EdgeType::Passage)
-                    == &EdgeType::Wall
-            {
+            if active_coord.y == (dimensions.height - 1) {
                 return None;
             };
             return Some(Coords {
@@ -337,9 +748,7 @@ pub fn adjust_in_direction(
             });
         }
         MapDirection::West => {
-            if active_coord.x == 0
-                || edges.get(&MapDirection::West).unwrap_or(&EdgeType::Passage) == &EdgeType::Wall
-            {
+            if active_coord.x == 0 {
                 return None;
             };
             return Some(Coords {
@@ -350,6 +759,82 @@ pub fn adjust_in_direction(
     }
 }
 
-pub fn get_dimensions() -> (usize, usize) {
-    return (map_generator::WIDTH, map_generator::HEIGHT);
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::map_responder::{Command, Inputs, PlayerCommand, PlayerInput};
+    use super::*;
+
+    fn no_inputs() -> Inputs {
+        Inputs { north: false, east: false, south: false, west: false, interact: false }
+    }
+
+    #[test]
+    fn kicking_a_teleported_player_leaves_nothing_behind_for_the_tick() {
+        let mut map = map_generator::generate_sized_map(5);
+        map.register_player("ann");
+        assert!(map.teleport_player("ann", Coords { x: 1, y: 1 }));
+        assert!(map.remove_player("ann"));
+
+        // Input the kicked player sent before the kick was drained
+        let inputs = vec![PlayerInput { user_id: "ann".to_string(), input: no_inputs() }];
+        let changes = map.tick(inputs, Vec::new(), Instant::now());
+
+        assert!(!changes.player_ids.contains(&"ann".to_string()));
+        assert!(map.players().is_empty());
+    }
+
+    #[test]
+    fn corner_plots_are_gated_with_a_one_way_passage_out() {
+        let map = map_generator::generate_sized_map(5);
+        // The wall between the top left corner plot and the plot east of it
+        assert_eq!(map.edges.get(&Coords { x: 4, y: 2 }, &MapDirection::East), EdgeType::Gate);
+        assert_eq!(map.edges.get(&Coords { x: 4, y: 3 }, &MapDirection::East), EdgeType::OneWay(MapDirection::East));
+
+        let inside = serde_json::to_value(map.cell_view(map.dimensions.index(&Coords { x: 4, y: 3 }))).unwrap();
+        let outside = serde_json::to_value(map.cell_view(map.dimensions.index(&Coords { x: 5, y: 3 }))).unwrap();
+        assert_eq!(inside["edges"]["East"], "OneWayOut");
+        assert_eq!(outside["edges"]["West"], "OneWayIn");
+    }
+
+    #[test]
+    fn cells_are_sent_with_how_long_they_have_been_growing() {
+        let mut map = map_generator::generate_sized_map(5);
+        map.change_cell_type(0, CellType::Plant);
+        for _ in 0..3 {
+            map.tick(Vec::new(), Vec::new(), Instant::now());
+        }
+
+        let plant = serde_json::to_value(map.cell_view(0)).unwrap();
+        assert_eq!(plant["cell_type"], "Plant");
+        assert_eq!(plant["lifetime"], 3);
+        assert!(plant.get("changed_at").is_none());
+        assert_eq!(serde_json::to_value(map.cell_view(1)).unwrap()["lifetime"], 0);
+    }
+
+    #[test]
+    fn walking_to_where_the_player_stands_leaves_them_idle() {
+        let mut map = map_generator::generate_sized_map(5);
+        let coords = map.register_player("ann").coords.clone();
+        let walk = PlayerCommand {
+            user_id: "ann".to_string(),
+            command: Command::WalkTo { x: coords.x, y: coords.y },
+        };
+        map.tick(Vec::new(), vec![walk], Instant::now());
+
+        let ann = &map.player_state["ann"];
+        assert_eq!(ann.state, PlayerStates::Idle);
+        assert!(ann.route.is_empty());
+    }
+
+    #[test]
+    fn opening_a_door_opens_up_routes_through_it() {
+        let mut map = map_generator::generate_sized_map(5);
+        // Either side of the door between the west plot and the middle one
+        let (west, middle) = (Coords { x: 4, y: 7 }, Coords { x: 5, y: 7 });
+        assert_eq!(map.edges.get(&west, &MapDirection::East), EdgeType::Door);
+        assert_eq!(map.find_path(&west, &middle), None);
+
+        map.change_edge(&west, &MapDirection::East, EdgeType::OpenDoor);
+        assert_eq!(map.find_path(&west, &middle), Some(vec![MapDirection::East]));
+    }
+}
@@ -0,0 +1,110 @@
+use rand::Rng;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::map::edges::EdgeGrid;
+use crate::map::pathfinding;
+use crate::map::{adjust_in_direction, Cell, CellType, Coords, EntityId, MapDirection};
+
+// How far an animal will go looking for something to eat
+const FORAGE_STEPS: usize = 6;
+// How far away a wandering entity picks its next destination
+const ANIMAL_WANDER_RADIUS: usize = 8;
+const MERCHANT_WANDER_RADIUS: usize = 3;
+
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NpcKind {
+    // Wanders the map eating any plants and flowers it comes across
+    Animal,
+    // Stays near its stall and trades keys for flowers
+    Merchant,
+}
+
+// A computer controlled entity
+#[derive(Serialize, Debug, Clone)]
+pub struct Npc {
+    pub entity_id: EntityId,
+    pub kind: NpcKind,
+    pub coords: Coords,
+    direction: MapDirection,
+    #[serde(skip_serializing)]
+    home: Coords,
+    #[serde(skip_serializing)]
+    path: VecDeque<MapDirection>,
+    #[serde(skip_serializing)]
+    last_moved: Instant,
+}
+
+impl Npc {
+    pub fn new(entity_id: EntityId, kind: NpcKind, coords: Coords) -> Npc {
+        Npc {
+            entity_id,
+            kind,
+            home: coords.clone(),
+            coords,
+            direction: MapDirection::South,
+            path: VecDeque::new(),
+            last_moved: Instant::now(),
+        }
+    }
+
+    // Run the entity's behaviour for this frame, returning true if it moved
+    pub fn update(&mut self, cells: &[Cell], edges: &EdgeGrid, frame_time: Instant) -> bool {
+        if self.last_moved + self.move_interval() > frame_time {
+            return false;
+        }
+        if self.path.is_empty() {
+            self.plan(cells, edges);
+        }
+        let direction = match self.path.pop_front() {
+            Some(direction) => direction,
+            None => return false,
+        };
+        self.last_moved = frame_time;
+        self.direction = direction;
+        match adjust_in_direction(&self.coords, &direction, edges) {
+            Some(coords) => self.coords = coords,
+            // Something like a door closed in our way, so pick a new route next time
+            None => self.path.clear(),
+        };
+        true
+    }
+
+    // Whether the entity wants to eat whatever is growing where it stands
+    pub fn eats(&self, cell: &Cell) -> bool {
+        self.kind == NpcKind::Animal && (cell.cell_type == CellType::Plant || cell.cell_type == CellType::Flower)
+    }
+
+    fn move_interval(&self) -> Duration {
+        match self.kind {
+            NpcKind::Animal => Duration::from_millis(400),
+            NpcKind::Merchant => Duration::from_millis(1000),
+        }
+    }
+
+    fn plan(&mut self, cells: &[Cell], edges: &EdgeGrid) {
+        let dimensions = edges.dimensions();
+        let path = match self.kind {
+            NpcKind::Animal => pathfinding::find_nearest(edges, &self.coords, FORAGE_STEPS, |coords| {
+                self.eats(&cells[dimensions.index(coords)])
+            })
+            .or_else(|| self.wander(edges, ANIMAL_WANDER_RADIUS)),
+            NpcKind::Merchant => self.wander(edges, MERCHANT_WANDER_RADIUS),
+        };
+        if let Some(path) = path {
+            self.path = path.into_iter().collect();
+        }
+    }
+
+    // A route to a random spot near home, if it can be reached
+    fn wander(&self, edges: &EdgeGrid, radius: usize) -> Option<Vec<MapDirection>> {
+        let dimensions = edges.dimensions();
+        let mut rng = rand::thread_rng();
+        let target = Coords {
+            x: rng.gen_range(self.home.x.saturating_sub(radius)..=(self.home.x + radius).min(dimensions.width - 1)),
+            y: rng.gen_range(self.home.y.saturating_sub(radius)..=(self.home.y + radius).min(dimensions.height - 1)),
+        };
+        pathfinding::find_path(edges, &self.coords, &target, radius * 4)
+    }
+}
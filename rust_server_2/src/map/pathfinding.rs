@@ -0,0 +1,254 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+use crate::map::{adjust_in_direction, step_in_direction, Coords, MapDirection};
+use crate::map::edges::EdgeGrid;
+
+// Cached routes are dropped wholesale once there are this many
+const MAX_CACHED_PATHS: usize = 1024;
+
+type CameFrom = HashMap<Coords, Option<(Coords, MapDirection)>>;
+
+// Answers routing questions about a map, remembering results until an edge
+// changes and they have to be thrown away
+#[derive(Debug, Default)]
+pub struct Pathfinder {
+    paths: HashMap<(Coords, Coords), Option<Vec<MapDirection>>>,
+    // Cells that are connected ignoring the direction of one-way edges share
+    // a region, so cells in different regions can never reach each other
+    regions: Option<Vec<usize>>,
+}
+
+impl Pathfinder {
+    pub fn new() -> Pathfinder {
+        Pathfinder {
+            paths: HashMap::new(),
+            regions: None,
+        }
+    }
+
+    // Must be called whenever an edge changes
+    pub fn invalidate(&mut self) {
+        self.paths.clear();
+        self.regions = None;
+    }
+
+    pub fn path(&mut self, edges: &EdgeGrid, from: &Coords, to: &Coords) -> Option<Vec<MapDirection>> {
+        let key = (from.clone(), to.clone());
+        if let Some(path) = self.paths.get(&key) {
+            return path.clone();
+        }
+        let path = if self.same_region(edges, from, to) {
+            find_path(edges, from, to, usize::MAX)
+        } else {
+            None
+        };
+        if self.paths.len() >= MAX_CACHED_PATHS {
+            self.paths.clear();
+        }
+        self.paths.insert(key, path.clone());
+        path
+    }
+
+    fn same_region(&mut self, edges: &EdgeGrid, a: &Coords, b: &Coords) -> bool {
+        let dimensions = edges.dimensions();
+        let regions = self.regions.get_or_insert_with(|| label_regions(edges));
+        regions[dimensions.index(a)] == regions[dimensions.index(b)]
+    }
+}
+
+// Shortest route between two cells that respects walls and closed doors,
+// giving up on anything further than `max_steps` away
+pub fn find_path(edges: &EdgeGrid, from: &Coords, to: &Coords, max_steps: usize) -> Option<Vec<MapDirection>> {
+    // A* ordered by steps taken plus the manhattan distance still to go
+    let mut came_from: CameFrom = HashMap::new();
+    let mut steps_to: HashMap<Coords, usize> = HashMap::new();
+    let mut open: BinaryHeap<Reverse<(usize, usize, usize)>> = BinaryHeap::new();
+    let dimensions = edges.dimensions();
+
+    came_from.insert(from.clone(), None);
+    steps_to.insert(from.clone(), 0);
+    open.push(Reverse((distance(from, to), 0, dimensions.index(from))));
+
+    while let Some(Reverse((_, steps, index))) = open.pop() {
+        let coords = dimensions.coords(index);
+        if coords == *to {
+            return Some(walk_back(&came_from, coords));
+        }
+        // Skip entries made stale by finding a shorter route to the same cell
+        if steps > steps_to[&coords] || steps == max_steps {
+            continue;
+        }
+        for direction in MapDirection::all().iter() {
+            if let Some(next) = adjust_in_direction(&coords, direction, edges) {
+                let next_steps = steps + 1;
+                if steps_to.get(&next).map_or(true, |known| next_steps < *known) {
+                    steps_to.insert(next.clone(), next_steps);
+                    came_from.insert(next.clone(), Some((coords.clone(), *direction)));
+                    open.push(Reverse((next_steps + distance(&next, to), next_steps, dimensions.index(&next))));
+                }
+            }
+        }
+    }
+    None
+}
+
+// Breadth first search for the closest cell matching `is_target`
+pub fn find_nearest<F>(edges: &EdgeGrid, from: &Coords, max_steps: usize, is_target: F) -> Option<Vec<MapDirection>>
+where
+    F: Fn(&Coords) -> bool,
+{
+    // Each visited cell remembers the cell and direction it was reached from
+    let mut came_from: CameFrom = HashMap::new();
+    let mut frontier: VecDeque<(Coords, usize)> = VecDeque::new();
+    came_from.insert(from.clone(), None);
+    frontier.push_back((from.clone(), 0));
+
+    while let Some((coords, steps)) = frontier.pop_front() {
+        if is_target(&coords) {
+            return Some(walk_back(&came_from, coords));
+        }
+        if steps == max_steps {
+            continue;
+        }
+        for direction in MapDirection::all().iter() {
+            if let Some(next) = adjust_in_direction(&coords, direction, edges) {
+                if !came_from.contains_key(&next) {
+                    came_from.insert(next.clone(), Some((coords.clone(), *direction)));
+                    frontier.push_back((next, steps + 1));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn distance(a: &Coords, b: &Coords) -> usize {
+    a.x.abs_diff(b.x) + a.y.abs_diff(b.y)
+}
+
+fn walk_back(came_from: &CameFrom, mut coords: Coords) -> Vec<MapDirection> {
+    let mut path: Vec<MapDirection> = Vec::new();
+    while let Some(Some((previous, direction))) = came_from.get(&coords) {
+        path.push(*direction);
+        coords = previous.clone();
+    }
+    path.reverse();
+    path
+}
+
+// Flood fills the map, treating any edge that can be crossed one way as
+// crossable both ways, and labels every cell with its region
+fn label_regions(edges: &EdgeGrid) -> Vec<usize> {
+    let dimensions = edges.dimensions();
+    let mut regions: Vec<Option<usize>> = vec![None; dimensions.size()];
+    let mut region = 0;
+    for start in 0..dimensions.size() {
+        if regions[start].is_some() {
+            continue;
+        }
+        regions[start] = Some(region);
+        let mut frontier: Vec<usize> = vec![start];
+        while let Some(index) = frontier.pop() {
+            let coords = dimensions.coords(index);
+            for direction in MapDirection::all().iter() {
+                let next = adjust_in_direction(&coords, direction, edges).or_else(|| {
+                    step_in_direction(&coords, direction, dimensions)
+                        .filter(|next| adjust_in_direction(next, &direction.opposite(), edges).is_some())
+                });
+                if let Some(next) = next {
+                    let next_index = dimensions.index(&next);
+                    if regions[next_index].is_none() {
+                        regions[next_index] = Some(region);
+                        frontier.push(next_index);
+                    }
+                }
+            }
+        }
+        region += 1;
+    }
+    regions.into_iter().map(|region| region.unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::{Dimensions, EdgeType};
+
+    fn open_grid(width: usize, height: usize) -> EdgeGrid {
+        EdgeGrid::new(Dimensions { width, height })
+    }
+
+    // Where following the route from `from` ends up, None if an edge is in the way
+    fn follow(edges: &EdgeGrid, from: &Coords, route: &[MapDirection]) -> Option<Coords> {
+        route.iter().try_fold(from.clone(), |coords, direction| adjust_in_direction(&coords, direction, edges))
+    }
+
+    #[test]
+    fn routes_go_around_walls() {
+        // A wall down the middle of the grid with a gap at the bottom
+        let mut edges = open_grid(5, 5);
+        for y in 0..4 {
+            edges.set(&Coords { x: 2, y }, &MapDirection::East, EdgeType::Wall);
+        }
+        let (from, to) = (Coords { x: 0, y: 0 }, Coords { x: 4, y: 0 });
+
+        let route = find_path(&edges, &from, &to, usize::MAX).unwrap();
+        assert_eq!(follow(&edges, &from, &route), Some(to));
+        assert_eq!(route.len(), 12);
+    }
+
+    #[test]
+    fn one_way_edges_are_only_crossed_one_way() {
+        let mut edges = open_grid(3, 1);
+        edges.set(&Coords { x: 1, y: 0 }, &MapDirection::East, EdgeType::OneWay(MapDirection::East));
+        let (west, east) = (Coords { x: 0, y: 0 }, Coords { x: 2, y: 0 });
+
+        assert_eq!(find_path(&edges, &west, &east, usize::MAX), Some(vec![MapDirection::East; 2]));
+        assert_eq!(find_path(&edges, &east, &west, usize::MAX), None);
+        // Both sides still share a region, the route just can't come back
+        let regions = label_regions(&edges);
+        assert_eq!(regions[0], regions[2]);
+    }
+
+    #[test]
+    fn searches_give_up_past_max_steps() {
+        let edges = open_grid(5, 1);
+        let (from, to) = (Coords { x: 0, y: 0 }, Coords { x: 4, y: 0 });
+        assert_eq!(find_path(&edges, &from, &to, 3), None);
+        assert!(find_path(&edges, &from, &to, 4).is_some());
+        assert_eq!(find_nearest(&edges, &from, 3, |coords| *coords == to), None);
+    }
+
+    #[test]
+    fn walled_off_cells_are_in_their_own_region() {
+        let mut edges = open_grid(3, 3);
+        let corner = Coords { x: 2, y: 2 };
+        edges.set(&corner, &MapDirection::North, EdgeType::Wall);
+        edges.set(&corner, &MapDirection::West, EdgeType::Wall);
+
+        let regions = label_regions(&edges);
+        let dimensions = edges.dimensions();
+        assert_ne!(regions[0], regions[dimensions.index(&corner)]);
+        assert_eq!(Pathfinder::new().path(&edges, &Coords { x: 0, y: 0 }, &corner), None);
+    }
+
+    #[test]
+    fn cached_routes_are_dropped_when_an_edge_changes() {
+        let mut edges = open_grid(2, 1);
+        edges.set(&Coords { x: 0, y: 0 }, &MapDirection::East, EdgeType::Door);
+        let (from, to) = (Coords { x: 0, y: 0 }, Coords { x: 1, y: 0 });
+        let mut pathfinder = Pathfinder::new();
+        assert_eq!(pathfinder.path(&edges, &from, &to), None);
+
+        edges.set(&from, &MapDirection::East, EdgeType::OpenDoor);
+        // Still the answer from before the door opened
+        assert_eq!(pathfinder.path(&edges, &from, &to), None);
+        pathfinder.invalidate();
+        assert_eq!(pathfinder.path(&edges, &from, &to), Some(vec![MapDirection::East]));
+
+        edges.set(&from, &MapDirection::East, EdgeType::Gate);
+        pathfinder.invalidate();
+        assert_eq!(pathfinder.path(&edges, &from, &to), None);
+    }
+}
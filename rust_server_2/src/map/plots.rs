@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::map::{Coords, Dimensions};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct Plot {
+    pub owner: Option<String>,
+    // Players the owner has invited to farm the plot
+    pub friends: Vec<String>,
+}
+
+impl Plot {
+    fn can_farm(&self, user_id: &str) -> bool {
+        match &self.owner {
+            Some(owner) => owner == user_id || self.friends.iter().any(|friend| friend == user_id),
+            None => true,
+        }
+    }
+}
+
+// Who owns each of the plots the map is divided into, ordered row by row.
+// Changes are marked unsaved for the game loop to hand to a `PlotSaver`, so
+// claims survive a restart without the tick waiting on the disk.
+#[derive(Debug)]
+pub struct Plots {
+    plot_side: usize,
+    plots_per_row: usize,
+    plots: Vec<Plot>,
+    unsaved: bool,
+}
+
+impl Plots {
+    // As many whole plots as fit in the map
+    pub fn new(plot_side: usize, dimensions: Dimensions) -> Plots {
+        let plots_per_row = dimensions.width / plot_side;
+        let plot_count = plots_per_row * (dimensions.height / plot_side);
+        Plots {
+            plot_side,
+            plots_per_row,
+            plots: vec![Plot::default(); plot_count],
+            unsaved: false,
+        }
+    }
+
+    // Restore claims saved at `path`, starting fresh if there are none or
+    // they were saved for a differently sized map
+    pub fn load(&mut self, path: &Path) {
+        match fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str::<Vec<Plot>>(&contents) {
+                Ok(plots) if plots.len() == self.plots.len() => self.plots = plots,
+                Ok(_) => warn!(?path, "ignoring saved plots, plot count does not match the map"),
+                Err(e) => error!(?path, error = %e, "could not parse saved plots"),
+            },
+            Err(e) => info!(?path, error = %e, "no saved plots loaded"),
+        }
+    }
+
+    pub fn all(&self) -> &[Plot] {
+        &self.plots
+    }
+
+    pub fn index_at(&self, coords: &Coords) -> usize {
+        (coords.y / self.plot_side) * self.plots_per_row + coords.x / self.plot_side
+    }
+
+    pub fn can_farm(&self, user_id: &str, coords: &Coords) -> bool {
+        self.plots[self.index_at(coords)].can_farm(user_id)
+    }
+
+    // Each player may own a single plot, and only plots nobody owns can be claimed
+    pub fn claim(&mut self, user_id: &str, coords: &Coords) -> bool {
+        let index = self.index_at(coords);
+        if self.plots[index].owner.is_some() || self.owned_by(user_id).is_some() {
+            return false;
+        }
+        self.plots[index] = Plot {
+            owner: Some(user_id.to_string()),
+            friends: Vec::new(),
+        };
+        self.unsaved = true;
+        true
+    }
+
+    pub fn abandon(&mut self, user_id: &str) -> bool {
+        match self.owned_by(user_id) {
+            Some(index) => {
+                self.plots[index] = Plot::default();
+                self.unsaved = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn invite(&mut self, user_id: &str, friend_id: &str) -> bool {
+        match self.owned_by(user_id) {
+            Some(index) if friend_id != user_id && !self.plots[index].friends.iter().any(|friend| friend == friend_id) => {
+                self.plots[index].friends.push(friend_id.to_string());
+                self.unsaved = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn uninvite(&mut self, user_id: &str, friend_id: &str) -> bool {
+        match self.owned_by(user_id) {
+            Some(index) if self.plots[index].friends.iter().any(|friend| friend == friend_id) => {
+                self.plots[index].friends.retain(|friend| friend != friend_id);
+                self.unsaved = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn reset(&mut self, index: usize) {
+        self.plots[index] = Plot::default();
+        self.unsaved = true;
+    }
+
+    // The top left and bottom right cells of a plot
+    pub fn bounds(&self, index: usize) -> Option<(Coords, Coords)> {
+        if index >= self.plots.len() {
+            return None;
+        }
+        let top_left = Coords {
+            x: (index % self.plots_per_row) * self.plot_side,
+            y: (index / self.plots_per_row) * self.plot_side,
+        };
+        let bottom_right = Coords {
+            x: top_left.x + self.plot_side - 1,
+            y: top_left.y + self.plot_side - 1,
+        };
+        Some((top_left, bottom_right))
+    }
+
+    fn owned_by(&self, user_id: &str) -> Option<usize> {
+        self.plots.iter().position(|plot| plot.owner.as_deref() == Some(user_id))
+    }
+
+    // The plots as JSON if they changed since this was last called
+    pub fn take_unsaved(&mut self) -> Option<String> {
+        if !self.unsaved {
+            return None;
+        }
+        self.unsaved = false;
+        Some(self.to_json())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.plots).unwrap()
+    }
+}
+
+// Writes plots on a blocking thread, one save at a time. Saves queued while
+// one is being written are collapsed into the latest.
+pub struct PlotSaver {
+    path: PathBuf,
+    sender: watch::Sender<Option<String>>,
+    task: JoinHandle<()>,
+}
+
+impl PlotSaver {
+    pub fn spawn(path: PathBuf) -> PlotSaver {
+        let (sender, mut receiver) = watch::channel::<Option<String>>(None);
+        let task_path = path.clone();
+        let task = tokio::spawn(async move {
+            while receiver.changed().await.is_ok() {
+                let contents = receiver.borrow().clone();
+                if let Some(contents) = contents {
+                    let path = task_path.clone();
+                    let _ = tokio::task::spawn_blocking(move || write(&path, &contents)).await;
+                }
+            }
+        });
+        PlotSaver { path, sender, task }
+    }
+
+    pub fn save(&self, contents: String) {
+        let _ = self.sender.send(Some(contents));
+    }
+
+    // Waits for any save in progress, then writes the final plots in place
+    pub async fn finish(self, contents: String) {
+        drop(self.sender);
+        let _ = self.task.await;
+        write(&self.path, &contents);
+    }
+}
+
+fn write(path: &Path, contents: &str) {
+    if let Err(e) = fs::write(path, contents) {
+        error!(?path, error = %e, "could not save plots");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_the_plots_that_fit_in_the_map() {
+        let plots = Plots::new(5, Dimensions { width: 20, height: 10 });
+        assert_eq!(plots.all().len(), 8);
+        assert_eq!(plots.index_at(&Coords { x: 19, y: 9 }), 7);
+    }
+
+    #[tokio::test]
+    async fn claims_are_saved_once_handed_to_the_saver() {
+        let path = std::env::temp_dir().join(format!("battista-plots-{}.json", std::process::id()));
+        let mut plots = Plots::new(5, Dimensions { width: 15, height: 15 });
+        assert!(plots.take_unsaved().is_none());
+
+        assert!(plots.claim("ann", &Coords { x: 0, y: 0 }));
+        let saver = PlotSaver::spawn(path.clone());
+        saver.save(plots.take_unsaved().unwrap());
+        assert!(plots.take_unsaved().is_none());
+        saver.finish(plots.to_json()).await;
+
+        let mut restored = Plots::new(5, Dimensions { width: 15, height: 15 });
+        restored.load(&path);
+        assert_eq!(restored.all()[0].owner.as_deref(), Some("ann"));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,41 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// Cells that change on their own (e.g. plants growing into flowers) register
+// the tick they are next due here, so a frame only touches cells with pending
+// changes instead of scanning the whole map.
+#[derive(Debug, Default)]
+pub struct Schedule {
+    events: BinaryHeap<Reverse<(u64, usize)>>,
+}
+
+impl Schedule {
+    pub fn new() -> Schedule {
+        Schedule {
+            events: BinaryHeap::new(),
+        }
+    }
+
+    pub fn schedule(&mut self, due_tick: u64, cell_index: usize) {
+        self.events.push(Reverse((due_tick, cell_index)));
+    }
+
+    // Removes and returns every cell index due at or before `tick`.
+    // Events may be stale (the cell changed again since it was scheduled),
+    // so callers must re-check the cell before acting on it.
+    pub fn pop_due(&mut self, tick: u64) -> Vec<usize> {
+        let mut due: Vec<usize> = Vec::new();
+        while let Some(Reverse((due_tick, cell_index))) = self.events.peek() {
+            if *due_tick > tick {
+                break;
+            }
+            due.push(*cell_index);
+            self.events.pop();
+        }
+        due
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+}
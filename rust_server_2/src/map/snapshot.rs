@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use tracing::{error, info};
+
+use crate::map::edges::EdgeGrid;
+use crate::map::{Cell, CellType, Dimensions, Map, Player, GROWTH_TICKS};
+
+// Everything about the world worth keeping across a restart. Plot claims are
+// saved separately as they change, and NPCs start afresh.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorldSnapshot {
+    tick: u64,
+    dimensions: Dimensions,
+    cells: Vec<Cell>,
+    edges: EdgeGrid,
+    players: Vec<Player>,
+}
+
+impl Map {
+    pub fn snapshot(&self) -> WorldSnapshot {
+        WorldSnapshot {
+            tick: self.tick,
+            dimensions: self.dimensions,
+            cells: self.cells.clone(),
+            edges: self.edges.clone(),
+            players: self.player_state.values().cloned().collect(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: WorldSnapshot) -> Result<(), String> {
+        if snapshot.dimensions != self.dimensions || snapshot.cells.len() != self.cells.len() {
+            return Err(String::from("snapshot was taken of a differently sized map"));
+        }
+        self.tick = snapshot.tick;
+        self.cells = snapshot.cells;
+        self.edges = snapshot.edges;
+        self.pathfinder.invalidate();
+        self.player_state = snapshot.players.into_iter().map(|player| (player.user_id.clone(), player)).collect();
+        // Keep entity ids unique across players restored from the snapshot and new entities
+        let highest_player_id = self.player_state.values().map(|player| player.entity_id).max().unwrap_or(0);
+        self.next_entity_id = self.next_entity_id.max(highest_player_id);
+
+        // Plants carry on growing from where they were
+        for cell in &self.cells {
+            if cell.cell_type == CellType::Plant {
+                self.schedule.schedule(cell.changed_at + GROWTH_TICKS, cell.index);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Writes to a temporary file first so a crash mid-write can't corrupt the last good snapshot
+pub fn save(snapshot: &WorldSnapshot, path: &Path) -> std::io::Result<()> {
+    let temporary_path = path.with_extension("tmp");
+    fs::write(&temporary_path, serde_json::to_string(snapshot)?)?;
+    fs::rename(&temporary_path, path)
+}
+
+pub fn load(path: &Path) -> Option<WorldSnapshot> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            info!(?path, error = %e, "no world snapshot loaded");
+            return None;
+        }
+    };
+    match serde_json::from_str(&contents) {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => {
+            error!(?path, error = %e, "could not parse world snapshot");
+            None
+        }
+    }
+}
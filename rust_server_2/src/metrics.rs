@@ -0,0 +1,118 @@
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+// Everything exposed on /metrics, registered once at startup and shared
+// between the game loop, the sockets and the HTTP handlers
+pub struct Metrics {
+    registry: Registry,
+    pub tick_duration: Histogram,
+    pub queued_requests: IntGauge,
+    pub tick_messages: Histogram,
+    pub tick_bytes: Histogram,
+    pub messages_sent: IntCounter,
+    pub bytes_sent: IntCounter,
+    pub connected_clients: IntGauge,
+    pub messages_received: IntCounter,
+    pub registrations: IntCounter,
+    pub http_requests: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("battista".to_string()), None).unwrap();
+
+        // Ticks are budgeted at tens of milliseconds, so start well below that
+        let tick_duration = Histogram::with_opts(
+            HistogramOpts::new("tick_duration_seconds", "Time spent running each game tick, excluding the sleep")
+                .buckets(exponential_buckets(0.000_1, 2.0, 12).unwrap()),
+        ).unwrap();
+        let queued_requests = IntGauge::new(
+            "map_requests_queued",
+            "Map requests waiting for the game loop at the start of the last tick",
+        ).unwrap();
+        let tick_messages = Histogram::with_opts(
+            HistogramOpts::new("tick_messages_sent", "Websocket messages sent by each tick")
+                .buckets(exponential_buckets(1.0, 4.0, 8).unwrap()),
+        ).unwrap();
+        let tick_bytes = Histogram::with_opts(
+            HistogramOpts::new("tick_bytes_sent", "Websocket payload bytes sent by each tick")
+                .buckets(exponential_buckets(64.0, 4.0, 10).unwrap()),
+        ).unwrap();
+        let messages_sent = IntCounter::new("messages_sent_total", "Websocket messages sent by the game loop").unwrap();
+        let bytes_sent = IntCounter::new("bytes_sent_total", "Websocket payload bytes sent by the game loop").unwrap();
+        let connected_clients = IntGauge::new("connected_clients", "Clients with an open websocket").unwrap();
+        let messages_received = IntCounter::new("messages_received_total", "Websocket messages received from clients").unwrap();
+        let registrations = IntCounter::new("registrations_total", "Clients registered through /register").unwrap();
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests answered, by method and status"),
+            &["method", "status"],
+        ).unwrap();
+
+        registry.register(Box::new(tick_duration.clone())).unwrap();
+        registry.register(Box::new(queued_requests.clone())).unwrap();
+        registry.register(Box::new(tick_messages.clone())).unwrap();
+        registry.register(Box::new(tick_bytes.clone())).unwrap();
+        registry.register(Box::new(messages_sent.clone())).unwrap();
+        registry.register(Box::new(bytes_sent.clone())).unwrap();
+        registry.register(Box::new(connected_clients.clone())).unwrap();
+        registry.register(Box::new(messages_received.clone())).unwrap();
+        registry.register(Box::new(registrations.clone())).unwrap();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+
+        Metrics {
+            registry,
+            tick_duration,
+            queued_requests,
+            tick_messages,
+            tick_bytes,
+            messages_sent,
+            bytes_sent,
+            connected_clients,
+            messages_received,
+            registrations,
+            http_requests,
+        }
+    }
+
+    // Renders every metric in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_encoded_under_the_battista_prefix() {
+        let metrics = Metrics::new();
+        metrics.registrations.inc();
+        metrics.http_requests.with_label_values(&["GET", "200"]).inc();
+        metrics.tick_duration.observe(0.002);
+
+        let encoded = metrics.encode();
+        assert!(encoded.contains("battista_registrations_total 1"));
+        assert!(encoded.contains(r#"battista_http_requests_total{method="GET",status="200"} 1"#));
+        assert!(encoded.contains("battista_tick_duration_seconds_count 1"));
+    }
+
+    #[test]
+    fn every_instance_has_its_own_registry() {
+        let first = Metrics::new();
+        let second = Metrics::new();
+        first.messages_sent.inc_by(3);
+        assert!(first.encode().contains("battista_messages_sent_total 3"));
+        assert!(second.encode().contains("battista_messages_sent_total 0"));
+    }
+}
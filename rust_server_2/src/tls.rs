@@ -0,0 +1,261 @@
+use crate::config::TlsConfig;
+use futures::Stream;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+// Connections that haven't finished their handshake by now are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to wait before accepting again after accept fails
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+// Terminates TLS with certificates that can be swapped out while running.
+// warp's own TLS server reads its certificate once, so we accept connections
+// ourselves and hand the decrypted streams to `run_incoming`.
+#[derive(Clone)]
+pub struct ReloadableAcceptor {
+    config: TlsConfig,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(config: TlsConfig) -> Result<Self, String> {
+        let acceptor = load_acceptor(&config)?;
+        Ok(ReloadableAcceptor {
+            config,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        })
+    }
+
+    // Existing connections keep the certificate they were accepted with
+    pub fn reload(&self) -> Result<(), String> {
+        let acceptor = load_acceptor(&self.config)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    fn current(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+}
+
+fn load_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let (cert_path, key_path) = match (&config.cert_path, &config.key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return Err("TLS needs both a certificate and a key".to_string()),
+    };
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid certificate or key: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("could not open {:?}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| format!("could not read certificates from {:?}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {:?}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    let file = File::open(path).map_err(|e| format!("could not open {:?}: {}", path, e))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|e| format!("could not read private key from {:?}: {}", path, e))?;
+    items.into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("no private key found in {:?}", path))
+}
+
+// Yields connections once their handshake completes, so a slow client
+// can't hold up anyone else's
+pub fn incoming(
+    listener: TcpListener,
+    acceptor: ReloadableAcceptor,
+) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+    incoming_with_timeout(listener, acceptor, HANDSHAKE_TIMEOUT)
+}
+
+fn incoming_with_timeout(
+    listener: TcpListener,
+    acceptor: ReloadableAcceptor,
+    handshake_timeout: Duration,
+) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+    let (sender, receiver) = mpsc::channel(32);
+    tokio::spawn(async move {
+        loop {
+            // Stop accepting once nobody is taking the connections
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = sender.closed() => break,
+            };
+            let (stream, addr) = match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    // Running out of file descriptors fails every accept until
+                    // some are freed, so don't retry straight away
+                    warn!(error = %e, "could not accept connection");
+                    sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let tls_acceptor = acceptor.current();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match timeout(handshake_timeout, tls_acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.send(Ok(stream)).await;
+                    }
+                    Ok(Err(e)) => debug!(%addr, error = %e, "TLS handshake failed"),
+                    Err(_) => debug!(%addr, "TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+// Picks up renewed certificates without dropping anyone who's connected
+#[cfg(unix)]
+pub async fn reload_on_sighup(acceptor: ReloadableAcceptor) {
+    use tokio::signal::unix::{signal, SignalKind};
+    use tracing::{error, info};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!(error = %e, "could not listen for SIGHUP, certificates won't be reloaded");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match acceptor.reload() {
+            Ok(()) => info!("reloaded TLS certificate"),
+            Err(e) => error!(error = %e, "could not reload TLS certificate, keeping the old one"),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_sighup(_acceptor: ReloadableAcceptor) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use tokio::io::AsyncReadExt;
+    use tokio_rustls::client;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio_rustls::TlsConnector;
+    use tokio_stream::StreamExt;
+
+    // Writes a fresh self-signed certificate for localhost into `dir`,
+    // returning it in DER so clients can trust it
+    fn write_self_signed(dir: &Path) -> Vec<u8> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        cert.serialize_der().unwrap()
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("battista-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn tls_config(dir: &Path) -> TlsConfig {
+        TlsConfig {
+            cert_path: Some(dir.join("cert.pem")),
+            key_path: Some(dir.join("key.pem")),
+        }
+    }
+
+    async fn connect(addr: SocketAddr, trusted: &[u8]) -> io::Result<client::TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(trusted.to_vec())).unwrap();
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        TlsConnector::from(Arc::new(config)).connect(server_name, stream).await
+    }
+
+    #[tokio::test]
+    async fn completes_a_handshake() {
+        let dir = test_dir("handshake");
+        let trusted = write_self_signed(&dir);
+        let acceptor = ReloadableAcceptor::new(tls_config(&dir)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut connections = Box::pin(incoming(listener, acceptor));
+
+        let (client, server) = tokio::join!(connect(addr, &trusted), connections.next());
+        assert!(client.is_ok());
+        assert!(matches!(server, Some(Ok(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_the_new_certificate_after_a_reload() {
+        let dir = test_dir("reload");
+        let old = write_self_signed(&dir);
+        let acceptor = ReloadableAcceptor::new(tls_config(&dir)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut connections = Box::pin(incoming(listener, acceptor.clone()));
+
+        // What SIGHUP does once the certificate has been renewed
+        let new = write_self_signed(&dir);
+        acceptor.reload().unwrap();
+
+        let (client, server) = tokio::join!(connect(addr, &new), connections.next());
+        assert!(client.is_ok());
+        assert!(matches!(server, Some(Ok(_))));
+        assert!(connect(addr, &old).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_connections_that_never_finish_the_handshake() {
+        let dir = test_dir("timeout");
+        write_self_signed(&dir);
+        let acceptor = ReloadableAcceptor::new(tls_config(&dir)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut connections = Box::pin(incoming_with_timeout(listener, acceptor, Duration::from_millis(100)));
+
+        // Connect without ever sending a ClientHello
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buffer = [0; 16];
+        let read = timeout(Duration::from_secs(5), stream.read(&mut buffer)).await;
+        assert!(matches!(read, Ok(Ok(0))), "the server should have closed the connection");
+        assert!(timeout(Duration::from_millis(50), connections.next()).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+// Everything the server broadcasts is published under one of these topics.
+// Clients subscribe to topic patterns where `*` matches any run of characters,
+// so `game.*` covers every game update and `*` covers everything.
+pub const PLAYER_UPDATES: &str = "game.players";
+pub const NPC_UPDATES: &str = "game.npcs";
+pub const CELL_UPDATES: &str = "game.cells";
+pub const EDGE_UPDATES: &str = "game.edges";
+pub const PLOT_UPDATES: &str = "game.plots";
+pub const CHAT: &str = "chat";
+pub const ADMIN_NOTICES: &str = "admin";
+
+const MAX_PATTERN_LENGTH: usize = 64;
+const MAX_SUBSCRIPTIONS: usize = 32;
+
+pub fn default_subscriptions() -> Vec<String> {
+    vec![String::from("game.*"), String::from(CHAT), String::from(ADMIN_NOTICES)]
+}
+
+// Greedy glob match: each `*` first matches nothing, and on a mismatch only
+// the most recent `*` is widened, so the time taken stays linear in the
+// length of the pattern times the length of the topic
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let (pattern, topic) = (pattern.as_bytes(), topic.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was in the pattern and how much of the topic it has taken
+    let mut star: Option<(usize, usize)> = None;
+    while t < topic.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == topic[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            star = Some((star_p, star_t + 1));
+            p = star_p + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Runs of `*` match the same as a single one
+fn collapse_stars(pattern: &str) -> String {
+    let mut collapsed = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        if !(c == '*' && collapsed.ends_with('*')) {
+            collapsed.push(c);
+        }
+    }
+    collapsed
+}
+
+// Adds the patterns to a client's subscriptions, failing without changing
+// anything if a pattern is unusable or there would be too many
+pub fn subscribe(subscriptions: &mut Vec<String>, patterns: &[String]) -> Result<(), String> {
+    for pattern in patterns {
+        if pattern.is_empty() || pattern.len() > MAX_PATTERN_LENGTH {
+            return Err(format!("topic pattern must be 1 to {} characters long", MAX_PATTERN_LENGTH));
+        }
+    }
+    let mut new_subscriptions = subscriptions.clone();
+    for pattern in patterns.iter().map(|pattern| collapse_stars(pattern)) {
+        if !new_subscriptions.contains(&pattern) {
+            new_subscriptions.push(pattern);
+        }
+    }
+    if new_subscriptions.len() > MAX_SUBSCRIPTIONS {
+        return Err(format!("no more than {} subscriptions are allowed", MAX_SUBSCRIPTIONS));
+    }
+    *subscriptions = new_subscriptions;
+    Ok(())
+}
+
+pub fn unsubscribe(subscriptions: &mut Vec<String>, patterns: &[String]) {
+    let patterns: Vec<String> = patterns.iter().map(|pattern| collapse_stars(pattern)).collect();
+    subscriptions.retain(|subscription| !patterns.contains(subscription));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn wildcards_match_any_run_of_characters() {
+        assert!(matches("game.*", PLAYER_UPDATES));
+        assert!(matches("*", CHAT));
+        assert!(matches("game.*s", CELL_UPDATES));
+        assert!(matches("*.cells", CELL_UPDATES));
+        assert!(matches(CHAT, CHAT));
+        assert!(!matches("game.*", CHAT));
+        assert!(!matches("game.cell", CELL_UPDATES));
+        assert!(!matches("*.players", CELL_UPDATES));
+        // The earlier wildcard has to give back what the later one needs
+        assert!(matches("g*e*s", EDGE_UPDATES));
+        assert!(matches("*a*ab", "xaaab"));
+        assert!(matches("game.**", "game."));
+        assert!(!matches("*a*ab", "xaaba"));
+        assert!(!matches("game.*x", PLAYER_UPDATES));
+    }
+
+    #[test]
+    fn defaults_cover_every_topic() {
+        let defaults = default_subscriptions();
+        for topic in [PLAYER_UPDATES, NPC_UPDATES, CELL_UPDATES, EDGE_UPDATES, PLOT_UPDATES, CHAT, ADMIN_NOTICES] {
+            assert!(defaults.iter().any(|pattern| matches(pattern, topic)), "{} is not subscribed", topic);
+        }
+    }
+
+    #[test]
+    fn subscribing_adds_each_pattern_once() {
+        let mut subscriptions = patterns(&[CHAT]);
+        subscribe(&mut subscriptions, &patterns(&["game.*", CHAT, "game.*"])).unwrap();
+        assert_eq!(subscriptions, patterns(&[CHAT, "game.*"]));
+
+        unsubscribe(&mut subscriptions, &patterns(&[CHAT]));
+        assert_eq!(subscriptions, patterns(&["game.*"]));
+    }
+
+    #[test]
+    fn runs_of_wildcards_are_collapsed_and_matched_quickly() {
+        let mut subscriptions = Vec::new();
+        let pattern = format!("{}x", "*".repeat(MAX_PATTERN_LENGTH - 1));
+        subscribe(&mut subscriptions, std::slice::from_ref(&pattern)).unwrap();
+        assert_eq!(subscriptions, patterns(&["*x"]));
+        unsubscribe(&mut subscriptions, std::slice::from_ref(&pattern));
+        assert!(subscriptions.is_empty());
+
+        // Even uncollapsed, the worst patterns don't blow up on a long topic
+        let alternating = format!("{}x", "*a".repeat(MAX_PATTERN_LENGTH / 2 - 1));
+        let topic = "a".repeat(1000);
+        let start = std::time::Instant::now();
+        assert!(!matches(&pattern, &topic));
+        assert!(!matches(&alternating, &topic));
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+    }
+
+    #[test]
+    fn bad_subscriptions_change_nothing() {
+        let mut subscriptions = patterns(&[CHAT]);
+        assert!(subscribe(&mut subscriptions, &patterns(&["game.*", ""])).is_err());
+        assert!(subscribe(&mut subscriptions, &["x".repeat(MAX_PATTERN_LENGTH + 1)]).is_err());
+
+        let too_many: Vec<String> = (0..MAX_SUBSCRIPTIONS).map(|i| format!("topic{}", i)).collect();
+        assert!(subscribe(&mut subscriptions, &too_many).is_err());
+        assert_eq!(subscriptions, patterns(&[CHAT]));
+    }
+}
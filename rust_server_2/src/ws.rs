@@ -1,29 +1,38 @@
-use crate::{Client, Clients};
+use crate::{topics, Client, Clients};
 use crate::map;
+use crate::metrics::Metrics;
 use futures::{FutureExt, StreamExt};
 use serde::Deserialize;
-use serde_json::from_str;
+use serde_json::json;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{info, instrument, trace, warn, Instrument};
 use warp::ws::{Message, WebSocket};
 
 
 #[derive(Deserialize, Debug)]
 pub struct TopicsRequest {
-    topics: Vec<String>,
+    pub topics: Vec<String>,
 }
 
+// Subscription changes a client can make over its own socket, e.g.
+// `{"type": "subscribe", "topics": ["game.*"]}`
 #[derive(Deserialize, Debug)]
-pub struct UserInput {
-    input: String,
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TopicsCommand {
+    Subscribe(TopicsRequest),
+    Unsubscribe(TopicsRequest),
 }
 
+#[instrument(name = "connection", skip_all, fields(session = %id, user_id = %client.user_id))]
 pub async fn client_connection(
         ws: WebSocket, 
         id: String, 
         clients: Clients, 
         mut tx: map::MapSender,
-        mut client: Client
+        mut client: Client,
+        metrics: Arc<Metrics>,
     ) {
     let (client_ws_sender, mut client_ws_rcv) = ws.split(); // Why is client_ws_rcv mut?
     let (client_sender, client_rcv) = mpsc::unbounded_channel();
@@ -31,15 +40,16 @@ pub async fn client_connection(
     
     tokio::task::spawn(client_rcv.forward(client_ws_sender).map(|result| {
         if let Err(e) = result {
-            eprintln!("error sending websocket msg: {}", e)
+            warn!(error = %e, "could not send websocket message")
         }
-    }));
+    }).in_current_span());
 
 
     client.sender = Some(client_sender);
     clients.write().await.insert(id.clone(), client);
 
-    println!("{} connected", id);
+    info!("connected");
+    metrics.connected_clients.inc();
     
     let cid = id.clone();
     let cclients = clients.clone();
@@ -48,25 +58,23 @@ pub async fn client_connection(
         let msg = match result {
             Ok(msg) => msg,
             Err(e) => {
-                eprintln!("error receiving ws message for id: {}): {}", id.clone(), e);
+                warn!(error = %e, "could not receive websocket message");
                 break;
             }
         };
+        metrics.messages_received.inc();
         let response = respond_to_client_msg(&id, msg, &mut tx, &clients).await;
-        match response {
-            Some(msg) => {
-            cclients.read().await
-                .get(&cid).unwrap()
-                .sender.as_ref().unwrap()
-                .send(Ok(Message::text(msg)))
-                .unwrap();
-            },
-            None => (),
+        if let Some(msg) = response {
+            // The client may have been kicked while we were responding
+            if let Some(sender) = cclients.read().await.get(&cid).and_then(|c| c.sender.as_ref()) {
+                let _ = sender.send(Ok(Message::text(msg)));
+            }
         }
     }
 
     clients.write().await.remove(&id);
-    println!("{} disconnected", id);
+    metrics.connected_clients.dec();
+    info!("disconnected");
 }
 
 async fn respond_to_client_msg(
@@ -74,7 +82,6 @@ async fn respond_to_client_msg(
         msg: Message, 
         tx: &mut map::MapSender,
         clients: &Clients) -> Option<String> {
-    // println!("received message from {}: {:?}", id, msg);
     let message = match msg.to_str() {
         Ok(v) => v,
         Err(_) => return None,
@@ -84,12 +91,32 @@ async fn respond_to_client_msg(
         return None;
     }
 
-    let client_lock = clients.read().await;
-    let user_id = client_lock.get(&id.to_string()).unwrap().user_id.clone();
+    // Subscriptions are kept with the client rather than the map
+    if let Ok(command) = serde_json::from_str::<TopicsCommand>(message) {
+        let mut client_lock = clients.write().await;
+        let client = client_lock.get_mut(id)?;
+        return Some(change_topics(client, command));
+    }
+
+    let user_id = clients.read().await.get(id)?.user_id.clone();
 
-    println!("{:?}", message);
-    map::map_responder::respond_to_player(tx, user_id.to_string(), message).await;
+    trace!(message, "received message");
+    map::map_responder::respond_to_player(tx, user_id, message).await;
 
     // return response;
     return None;
-}
\ No newline at end of file
+}
+
+fn change_topics(client: &mut Client, command: TopicsCommand) -> String {
+    let result = match command {
+        TopicsCommand::Subscribe(request) => topics::subscribe(&mut client.topics, &request.topics),
+        TopicsCommand::Unsubscribe(request) => {
+            topics::unsubscribe(&mut client.topics, &request.topics);
+            Ok(())
+        }
+    };
+    match result {
+        Ok(()) => json!({"type": "topics", "topics": &client.topics}).to_string(),
+        Err(reason) => json!({"type": "topics_error", "reason": reason}).to_string(),
+    }
+}
@@ -11,6 +11,21 @@ let other_players = {};
 let edgeTypes = {
     WALL: "Wall",
     PASSAGE: "Passage",
+    DOOR: "Door",
+    OPEN_DOOR: "OpenDoor",
+    GATE: "Gate",
+    OPEN_GATE: "OpenGate",
+    // Relative to the cell, whether it can be left or only entered that way
+    ONE_WAY_OUT: "OneWayOut",
+    ONE_WAY_IN: "OneWayIn",
+}
+
+let edgeColors = {
+    [edgeTypes.WALL]: "brown",
+    [edgeTypes.DOOR]: "orange",
+    [edgeTypes.GATE]: "grey",
+    [edgeTypes.ONE_WAY_OUT]: "green",
+    [edgeTypes.ONE_WAY_IN]: "red",
 }
 
 let cellTypes = {
@@ -27,14 +42,15 @@ let messageTypes = {
 function main(){
     document.getElementById('registration').addEventListener('submit', e => {
         let username = document.getElementById('username').value;
+        let password = document.getElementById('password').value;
         console.log(username);
-        submit(username);
+        submit(username, password);
         e.preventDefault();
     })
 }
 
-function submit(username){
-    registerUser(username)
+function submit(username, password){
+    registerUser(username, password)
     .then(data => {
         height = data.height;
         width = data.width;
@@ -57,6 +73,8 @@ function submit(username){
             drawOtherPlayers: drawOtherPlayers,
             drawRoom: drawRoom,
             drawWalls: drawWalls,
+            drawPlotOwners: drawPlotOwners,
+            drawNpcs: drawNpcs,
         }
 
         function start() {
@@ -118,32 +136,59 @@ function submit(username){
 
             ctx.lineWidth=wallWidth;
 
-            ctx.beginPath()
-            ctx.strokeStyle = "brown";
-            if (cell.edges.North == edgeTypes.WALL) {
-                ctx.moveTo(leftX, topY);
-                ctx.lineTo(rightX, topY);
+            // Open doors and open gates are left undrawn
+            const segments = {
+                North: [leftX, topY, rightX, topY],
+                East: [rightX, topY, rightX, bottomY],
+                South: [rightX, bottomY, leftX, bottomY],
+                West: [leftX, bottomY, leftX, topY],
             };
+            for (const direction in segments) {
+                const color = edgeColors[cell.edges[direction]];
+                if (color === undefined) {
+                    continue;
+                }
+                const [fromX, fromY, toX, toY] = segments[direction];
+                ctx.beginPath()
+                ctx.strokeStyle = color;
+                ctx.moveTo(fromX, fromY);
+                ctx.lineTo(toX, toY);
+                ctx.stroke();
+            }
+        }
 
-            if (cell.edges.East == edgeTypes.WALL) {
-                ctx.moveTo(rightX, topY);
-                ctx.lineTo(rightX, bottomY);
-            };
 
-            if (cell.edges.South == edgeTypes.WALL) {
-                ctx.moveTo(rightX, bottomY);
-                ctx.lineTo(leftX, bottomY);
-            };
+        function drawPlotOwners(plots) {
+            let plotSide = width / 3;
+            ctx.font = "16px monospace";
+            ctx.fillStyle = "white";
+            plots.forEach((plot, index) => {
+                if (plot.owner === null) {
+                    return;
+                }
+                let plotX = (index % 3) * plotSide * this.char_width;
+                let plotY = Math.floor(index / 3) * plotSide * this.char_height;
+                ctx.fillText(`Owner: ${plot.owner}`, plotX + this.char_width, plotY + this.char_height);
+            });
+        }
 
-            if (cell.edges.West == edgeTypes.WALL) {
-                ctx.moveTo(leftX, bottomY);
-                ctx.lineTo(leftX, topY);
+        function drawNpcs(npcs) {
+            let npcColors = {
+                Animal: "green",
+                Merchant: "gold",
             };
-
-            ctx.stroke();
+            let shrink = this.char_height / 5;
+            Object.values(npcs).forEach(npc => {
+                ctx.fillStyle = npcColors[npc.kind];
+                ctx.fillRect(
+                    npc.coords.x * this.char_width + shrink,
+                    npc.coords.y * this.char_height + shrink,
+                    this.char_width - shrink * 2,
+                    this.char_height - shrink * 2,
+                );
+            });
         }
 
-
         function drawPlayer(player_position, player_direction) {
             // console.log("Rendering player with pos: " + player_position.x + "," + player_position.y + " and direction: " + player_direction);
             let x = player_position.x;
@@ -257,6 +302,9 @@ function submit(username){
         Game.state = {}
         Game.state.player_position = data.player_position;
         Game.state.player_direction = data.player_direction;
+        Game.state.plots = data.plots;
+        Game.state.npcs = {};
+        data.npcs.forEach(npc => Game.state.npcs[npc.entity_id] = npc);
         Game.state.discoveredRooms = []
         for (const property in data.explored_cells){
             Game.state.discoveredRooms[property] = data.explored_cells[property]
@@ -271,6 +319,21 @@ function submit(username){
             msg = JSON.parse(msg);
             if (msg.type == "cell_update"){
                 msg.cells.forEach(cell => Game.state.discoveredRooms[cell.index] = cell)
+            } else if (msg.type == "chat"){
+                logChat(`${msg.whisper ? "(whisper) " : ""}${msg.from}: ${msg.text}`);
+            } else if (msg.type == "notice"){
+                let title = msg.notice.title ? `${msg.notice.title}: ` : "";
+                logChat(`[${msg.notice.level}] ${title}${msg.notice.body}`);
+            } else if (msg.type == "shutdown"){
+                logChat("The server is shutting down");
+            } else if (msg.type == "chat_error"){
+                logChat(`Message not sent: ${msg.reason}`);
+            } else if (msg.type == "npc_update"){
+                msg.npcs.forEach(npc => Game.state.npcs[npc.entity_id] = npc);
+            } else if (msg.type == "plot_update"){
+                Game.state.plots = msg.plots;
+            } else if (msg.type == "edge_update"){
+                msg.edges.forEach(edge => Game.state.discoveredRooms[edge.index].edges = edge.edges)
             } else if (msg.type == "player_update") {
                 msg.players.forEach(player => new_position=player.coords)
                 // Hack
@@ -299,6 +362,11 @@ function submit(username){
 
                 " ": "interact"
             }
+
+            // Keys that send a one-off command rather than an input state
+            var command_map = {
+                "c": {"type": "claim_plot"},
+            }
             
             const curInput = {
                 "north": false,
@@ -308,7 +376,27 @@ function submit(username){
                 "interact": false
             };
 
+            document.getElementById('chat').addEventListener('submit', e => {
+                e.preventDefault();
+                let input = document.getElementById('chat-text');
+                let whisper = input.value.match(/^\/w (\S+) (.*)$/);
+                if (whisper) {
+                    Game.socket.send(JSON.stringify({"type": "whisper", "user_id": whisper[1], "text": whisper[2]}));
+                } else {
+                    Game.socket.send(JSON.stringify({"type": "chat", "text": input.value}));
+                }
+                input.value = "";
+            });
+
             const keyDownHandler = (e) => {
+                // Let the chat box have its keys
+                if (e.target.tagName === "INPUT") {
+                    return;
+                }
+                if (command_map[e.key] !== undefined && !e.repeat) {
+                    Game.socket.send(JSON.stringify(command_map[e.key]));
+                    return;
+                }
                 command = control_map[e.key];
                 if (command !== undefined && !curInput[command]) {
                     console.log("Key pressed:", e.key);
@@ -321,6 +409,9 @@ function submit(username){
             }
 
             const keyUpHandler = (e) => {
+                if (e.target.tagName === "INPUT") {
+                    return;
+                }
                 command = control_map[e.key];
                 if (command !== undefined && curInput[command]) {
                     console.log("Key released:", e.key);
@@ -331,6 +422,15 @@ function submit(username){
                 }
             }
 
+            // Clicking a cell walks there
+            renderer.canvas.addEventListener("click", (e) => {
+                Game.socket.send(JSON.stringify({
+                    "type": "walk_to",
+                    "x": Math.floor(e.offsetX / renderer.char_width),
+                    "y": Math.floor(e.offsetY / renderer.char_height),
+                }));
+            }, false);
+
             document.addEventListener("keydown", keyDownHandler, false);
             document.addEventListener("keyup", keyUpHandler, false);
 
@@ -350,6 +450,8 @@ function submit(username){
                 Game.renderer.drawBackground();
                 this.state.discoveredRooms.forEach((room) => this.renderer.drawRoom(room));
                 this.state.discoveredRooms.forEach((room) => this.renderer.drawWalls(room));
+                this.renderer.drawPlotOwners(this.state.plots);
+                this.renderer.drawNpcs(this.state.npcs);
                 this.renderer.drawPlayer(this.state.player_position, this.state.player_direction);
                 this.renderer.drawOtherPlayers();
             }
@@ -380,37 +482,46 @@ function submit(username){
     })
 }
 
+function logChat(text) {
+    let entry = document.createElement('li');
+    entry.textContent = text;
+    document.getElementById('chat-log').prepend(entry);
+}
+
 function getIndexFromCoords(coords) {
     return coords.y * width + coords.x;
 }
 
-async function registerUser(username){
-    user_id = username.hashCode();
+// Logs in to message_server, creating the account the first time, then
+// registers with the token it hands back
+async function registerUser(username, password){
+    const messageServerUrl = 'http://localhost:9000/';
     const registerUrl = 'http://localhost:8000/register';
 
     const headers = new Headers({
         'Content-Type': 'application/json'
     })
+    const account = {username: username, password: password};
+    let login = await fetch(messageServerUrl, {
+        method: 'POST',
+        body: JSON.stringify({command: "login", content: account})
+    });
+    if (login.status === 401) {
+        login = await fetch(messageServerUrl, {
+            method: 'POST',
+            body: JSON.stringify({command: "createAccount", content: account})
+        });
+    }
+    const session = await login.json();
+    user_id = session.user_id;
     const response = await fetch(registerUrl, {
         method: 'POST',
         headers: headers,
-        body: JSON.stringify({user_id: user_id})
+        body: JSON.stringify({token: session.token})
     });
     return response.json();
 }
 
-String.prototype.hashCode = function() {
-    var hash = 0, i, chr;
-    if (this.length === 0) return hash;
-    for (i = 0; i < this.length; i++) {
-        chr   = this.charCodeAt(i);
-        hash  = ((hash << 5) - hash) + chr;
-        hash |= 0; // Convert to 32bit integer
-        hash = hash >>> 0;
-    }
-    return hash;
-};
-
 // Rendering
 
 function getCoordsFromIndex(i){